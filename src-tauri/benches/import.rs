@@ -0,0 +1,100 @@
+//! How fast game records are imported and how much memory their trees take, using the games in
+//! `tests/resources/games.pgn` scaled up. Run with `cargo bench --bench import`.
+//!
+//! Each generated game follows one of the real games for a while then carries on with random
+//! legal moves, so the tree grows like it would for a real archive rather than every game going
+//! down the same line.
+use chess_driller::{lines, OpeningDatabase, OpeningNode};
+use petgraph::graph::{Edge, Node};
+use pgn_reader::SanPlus;
+use shakmaty::{Chess, Color, Position};
+use std::fmt::Write;
+use std::fs;
+use std::mem::size_of;
+use std::time::Instant;
+
+const GAMES: &str = "tests/resources/games.pgn";
+const PLAYER: &str = "xd009642";
+const PLIES: usize = 40;
+const SIZES: [usize; 3] = [1_000, 10_000, 50_000];
+
+fn templates() -> Vec<Vec<SanPlus>> {
+    let db =
+        OpeningDatabase::load_multigame_pgn(fs::File::open(GAMES).unwrap(), PLAYER.into()).unwrap();
+    Color::ALL
+        .iter()
+        .flat_map(|color| {
+            let graph = db.graph(*color);
+            lines(graph)
+                .into_iter()
+                .map(|line| line.iter().map(|x| graph[*x].san.clone()).collect())
+        })
+        .collect()
+}
+
+fn scaled_pgn(templates: &[Vec<SanPlus>], games: usize) -> String {
+    let mut rng = fastrand::Rng::with_seed(games as u64);
+    let mut pgn = String::new();
+    for i in 0..games {
+        let template = &templates[i % templates.len()];
+        let keep = rng.usize(..=template.len().min(PLIES / 2));
+        let mut position = Chess::default();
+        let mut moves = vec![];
+        for san in &template[..keep] {
+            let mv = san.san.to_move(&position).unwrap();
+            moves.push(SanPlus::from_move_and_play_unchecked(&mut position, &mv));
+        }
+        while moves.len() < PLIES {
+            let legal = position.legal_moves();
+            if legal.is_empty() {
+                break;
+            }
+            let mv = legal[rng.usize(..legal.len())].clone();
+            moves.push(SanPlus::from_move_and_play_unchecked(&mut position, &mv));
+        }
+        let (white, black) = if i % 2 == 0 {
+            (PLAYER, "opponent")
+        } else {
+            ("opponent", PLAYER)
+        };
+        writeln!(
+            pgn,
+            "[White \"{}\"]\n[Black \"{}\"]\n[Result \"*\"]\n",
+            white, black
+        )
+        .unwrap();
+        for (ply, san) in moves.iter().enumerate() {
+            if ply % 2 == 0 {
+                write!(pgn, "{}. ", ply / 2 + 1).unwrap();
+            }
+            write!(pgn, "{} ", san).unwrap();
+        }
+        pgn.push_str("*\n\n");
+    }
+    pgn
+}
+
+fn main() {
+    let templates = templates();
+    let per_node = size_of::<Node<OpeningNode>>() + size_of::<Edge<()>>();
+    println!(
+        "{} bytes per node, {} for the move",
+        per_node,
+        size_of::<SanPlus>()
+    );
+    for games in SIZES {
+        let pgn = scaled_pgn(&templates, games);
+        let start = Instant::now();
+        let db = OpeningDatabase::load_multigame_pgn(pgn.as_bytes(), PLAYER.into()).unwrap();
+        let elapsed = start.elapsed();
+        let nodes = db.graph(Color::White).node_count() + db.graph(Color::Black).node_count();
+        println!(
+            "{:>6} games: {:>8.1?} {:>9.0} games/s {:>8} nodes ~{:.1} MiB",
+            games,
+            elapsed,
+            games as f64 / elapsed.as_secs_f64(),
+            nodes,
+            (nodes * per_node) as f64 / (1024.0 * 1024.0)
+        );
+    }
+}
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Replace openings.tsv with the full lichess chess-openings dataset (CC0,
+# https://github.com/lichess-org/chess-openings), the a-e files are joined
+# under a single header.
+set -eu
+cd "$(dirname "$0")"
+url=https://raw.githubusercontent.com/lichess-org/chess-openings/master
+tmp=$(mktemp)
+trap 'rm -f "$tmp"' EXIT
+printf 'eco\tname\tpgn\n' > "$tmp"
+for part in a b c d e; do
+    curl -sSf "$url/$part.tsv" | tail -n +2 >> "$tmp"
+done
+mv "$tmp" openings.tsv
+trap - EXIT
+echo "Wrote $(($(wc -l < openings.tsv) - 1)) openings to openings.tsv"
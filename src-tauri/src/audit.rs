@@ -0,0 +1,251 @@
+//! Check every move we play in the repertoire with an engine to find the dubious ones.
+use crate::db::{line_key, path_to};
+use crate::edit::Edit;
+use crate::engine::{to_uci, Engine, Evaluation};
+use crate::tree::OpeningGraph;
+use petgraph::Direction;
+use pgn_reader::{Nag, SanPlus};
+use shakmaty::fen::Epd;
+use shakmaty::{Chess, Color, EnPassantMode, Position};
+use std::collections::HashMap;
+use std::fmt;
+use tracing::{debug, info};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditedMove {
+    /// Moves up to and including ours
+    pub line: String,
+    pub played: String,
+    pub best: String,
+    /// Centipawns lost compared to the best move
+    pub loss: i32,
+    /// The move's NAGs with the audit's verdict replacing any existing `!`/`?` style ones
+    pub nags: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditReport {
+    pub color: Color,
+    pub depth: u32,
+    pub threshold: i32,
+    /// How many of our moves were checked
+    pub audited: usize,
+    /// Moves losing more than the threshold, worst first
+    pub flagged: Vec<AuditedMove>,
+}
+
+impl AuditReport {
+    /// Edits that put the audit's NAGs on the flagged moves
+    pub fn annotations(&self) -> Vec<Edit> {
+        self.flagged
+            .iter()
+            .map(|x| Edit::SetNags {
+                line: x.line.clone(),
+                nags: x.nags.clone(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Audited {} {} moves at depth {}, {} lose more than {}cp",
+            self.audited,
+            self.color,
+            self.depth,
+            self.flagged.len(),
+            self.threshold
+        )?;
+        for mv in &self.flagged {
+            writeln!(
+                f,
+                "  -{:<5} {} (played {}, best {})",
+                mv.loss, mv.line, mv.played, mv.best
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluate every move we make in the `color` repertoire. Moves losing more than `threshold`
+/// centipawns are dubious (`?!`) and losing double that makes them a mistake (`?`).
+pub fn audit(
+    color: Color,
+    openings: &OpeningGraph,
+    engine: &mut impl Engine,
+    depth: u32,
+    threshold: i32,
+) -> anyhow::Result<AuditReport> {
+    let mut cache: HashMap<Epd, Evaluation> = HashMap::new();
+    let mut evaluate = |position: &Chess| -> anyhow::Result<Evaluation> {
+        let epd = Epd::from_position(position.clone(), EnPassantMode::Legal);
+        if let Some(eval) = cache.get(&epd) {
+            return Ok(eval.clone());
+        }
+        let eval = engine.evaluate(position, depth)?;
+        cache.insert(epd, eval.clone());
+        Ok(eval)
+    };
+
+    let mut audited = 0;
+    let mut flagged = vec![];
+    let mut roots = openings.roots().to_vec();
+    roots.sort();
+    let mut stack = roots
+        .into_iter()
+        .rev()
+        .map(|x| (openings[x].start_position(), vec![x]))
+        .collect::<Vec<_>>();
+    while let Some((position, children)) = stack.pop() {
+        let ours = position.turn() == color;
+        let before = if ours && !children.is_empty() {
+            Some(evaluate(&position)?)
+        } else {
+            None
+        };
+        for child in children {
+            // The lint command reports illegal moves, here we just skip them
+            let Ok(mv) = openings[child].san.san.to_move(&position) else {
+                continue;
+            };
+            let mut next = position.clone();
+            next.play_unchecked(&mv);
+            if let Some(before) = before.as_ref() {
+                audited += 1;
+                if before.best.as_ref() != Some(&to_uci(&mv)) {
+                    let after = evaluate(&next)?;
+                    let loss = before.centipawns + after.centipawns;
+                    debug!("{} loses {}cp", openings[child].san, loss);
+                    if loss > threshold {
+                        let verdict = if loss >= 2 * threshold {
+                            Nag::MISTAKE
+                        } else {
+                            Nag::DUBIOUS_MOVE
+                        };
+                        let mut nags = openings[child]
+                            .nags()
+                            .iter()
+                            .filter(|x| !(1..=6).contains(&x.0))
+                            .map(|x| x.0)
+                            .collect::<Vec<_>>();
+                        nags.insert(0, verdict.0);
+                        let best = before
+                            .best
+                            .as_ref()
+                            .and_then(|x| x.to_move(&position).ok())
+                            .map(|x| SanPlus::from_move(position.clone(), &x).to_string())
+                            .unwrap_or_default();
+                        flagged.push(AuditedMove {
+                            line: line_key(openings, &path_to(openings, child)),
+                            played: openings[child].san.to_string(),
+                            best,
+                            loss,
+                            nags,
+                        });
+                    }
+                }
+            }
+            let mut grandchildren = openings
+                .neighbors_directed(child, Direction::Outgoing)
+                .collect::<Vec<_>>();
+            grandchildren.sort();
+            stack.push((next, grandchildren));
+        }
+    }
+    info!("Audited {} moves, {} flagged", audited, flagged.len());
+    flagged.sort_by(|a, b| b.loss.cmp(&a.loss).then(a.line.cmp(&b.line)));
+    Ok(AuditReport {
+        color,
+        depth,
+        threshold,
+        audited,
+        flagged,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{find_line, OpeningDatabase};
+    use crate::edit::apply_edits;
+    use shakmaty::uci::Uci;
+    use std::path::Path;
+
+    /// Gives evaluations for a few positions and calls everything else equal
+    struct Scripted(HashMap<Epd, Evaluation>);
+
+    fn play(moves: &str) -> Chess {
+        let mut position = Chess::default();
+        for san in moves.split_whitespace() {
+            let mv = SanPlus::from_ascii(san.as_bytes())
+                .unwrap()
+                .san
+                .to_move(&position)
+                .unwrap();
+            position.play_unchecked(&mv);
+        }
+        position
+    }
+
+    impl Scripted {
+        fn add(&mut self, moves: &str, best: Option<&str>, centipawns: i32) {
+            let epd = Epd::from_position(play(moves), EnPassantMode::Legal);
+            let best = best.map(|x| x.parse::<Uci>().unwrap());
+            self.0.insert(epd, Evaluation { best, centipawns });
+        }
+    }
+
+    impl Engine for Scripted {
+        fn evaluate(&mut self, position: &Chess, _depth: u32) -> anyhow::Result<Evaluation> {
+            let epd = Epd::from_position(position.clone(), EnPassantMode::Legal);
+            Ok(self.0.get(&epd).cloned().unwrap_or(Evaluation {
+                best: None,
+                centipawns: 0,
+            }))
+        }
+    }
+
+    #[test]
+    fn flag_moves() {
+        let main = "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5";
+        let mut engine = Scripted(HashMap::new());
+        engine.add(main, Some("d2d4"), 30);
+        engine.add(&format!("{} Qe2", main), None, 60);
+        let check = format!("{} Qe2 Bf2+ Kd1 Nxc3+", main);
+        engine.add(&check, Some("b2c3"), -20);
+        engine.add(&format!("{} dxc3", check), None, 200);
+
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let mut prep = db.prep(Color::White);
+        let report = audit(Color::White, &prep.graph, &mut engine, 10, 50).unwrap();
+        assert!(report.audited > 2);
+
+        let flagged = report
+            .flagged
+            .iter()
+            .map(|x| {
+                (
+                    x.played.as_str(),
+                    x.best.as_str(),
+                    x.loss,
+                    x.nags.as_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flagged,
+            vec![("dxc3", "bxc3", 180, &[2][..]), ("Qe2", "d4", 90, &[6][..])]
+        );
+
+        apply_edits(&mut prep, Color::White, &report.annotations());
+        let line = report.flagged[1]
+            .line
+            .split_whitespace()
+            .map(|x| SanPlus::from_ascii(x.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        let node = find_line(&prep.graph, None, &line).unwrap();
+        assert_eq!(prep.graph[node].nags(), [Nag::DUBIOUS_MOVE]);
+    }
+}
@@ -0,0 +1,286 @@
+//! Command line tools for looking over the repertoire without starting the app.
+use crate::audit::audit;
+use crate::clients::chess_com::{load_cached_games, ChessComClient};
+use crate::config::Config;
+use crate::db::OpeningDatabase;
+use crate::edit::RepertoireEdits;
+use crate::engine::UciEngine;
+use crate::export::{export_database, to_dot, to_tree, ExportFormat};
+use crate::lint::lint;
+use crate::masters::{masters_path, ImportSettings, MastersBook, MastersImporter};
+use crate::parallel::threads;
+use crate::polyglot::{export_book, write_book, BookWeights};
+use crate::scout::scout;
+use crate::stats::{DrillHistory, RepertoireStats};
+use clap::{Parser, Subcommand};
+use shakmaty::Color;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Parser)]
+#[command(about = "Drill opening repertoires from PGN files")]
+pub struct Args {
+    /// Leave out to launch the app
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Use this profile from the config instead of the last one used
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+    /// Read the config from here instead of the config directory
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Show how much of a repertoire has been drilled and how well it's known
+    Stats {
+        #[arg(default_value = "white")]
+        color: Color,
+    },
+    /// Check the repertoire for problems like having more than one move for us in a position
+    Lint {
+        /// Leave out to check both colours
+        color: Option<Color>,
+    },
+    /// Check our moves in a repertoire with a UCI engine and report the dubious ones. The engine
+    /// and its settings are taken from the config unless they're given here
+    Audit {
+        #[arg(default_value = "white")]
+        color: Color,
+        /// Path to the engine
+        #[arg(long)]
+        engine: Option<PathBuf>,
+        #[arg(long)]
+        depth: Option<u32>,
+        /// Flag moves losing more than this many centipawns
+        #[arg(long)]
+        threshold: Option<i32>,
+        /// Mark the flagged moves in the repertoire with `?!` or `?`
+        #[arg(long)]
+        annotate: bool,
+        /// Where to write the report, defaults to the data directory
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Write a repertoire out as a polyglot opening book. Books in the repertoire folders are
+    /// loaded like PGNs
+    ExportBook {
+        color: Color,
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        weights: BookWeights,
+    },
+    /// See which lines of a repertoire an opponent is likely to play into and where they leave it,
+    /// using their chess.com games
+    Scout {
+        /// Their chess.com username, other sites aren't supported so use `--pgn` for those
+        opponent: String,
+        /// The repertoire to check, their games with the other colour are used
+        #[arg(default_value = "white")]
+        color: Color,
+        /// How many months of games to download
+        #[arg(long, default_value_t = 6)]
+        months: usize,
+        /// Read their games from a PGN file instead of downloading them
+        #[arg(long)]
+        pgn: Option<PathBuf>,
+    },
+    /// Write out a repertoire's graph as GraphViz DOT or a JSON tree
+    Export {
+        color: Color,
+        /// Only export the moves after this line, e.g. "e4 e5 Nf3". Lines from a set up position
+        /// start with its FEN and a `|`
+        #[arg(long)]
+        moves: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// Print to stdout if not given
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Add games from PGN dumps like the lichess database or TWIC to the masters book, which the
+    /// app uses to show what strong players play and to pick the computer's moves
+    ImportMasters {
+        /// PGN files to read, `-` reads from stdin so compressed dumps can be piped in
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Only keep this many plies of each game
+        #[arg(long, default_value_t = 24)]
+        plies: usize,
+        /// Skip games where either player is rated below this
+        #[arg(long, default_value_t = 2200)]
+        min_rating: u16,
+        /// Where to write the book, defaults to the profile's data directory
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+fn load_config(profile: Option<&str>, path: Option<&Path>) -> anyhow::Result<Config> {
+    let mut config = Config::load_from(path)?;
+    if let Some(profile) = profile {
+        config.use_profile(profile)?;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+pub fn run(command: Command, profile: Option<&str>, config: Option<&Path>) -> anyhow::Result<()> {
+    match command {
+        Command::Stats { color } => {
+            let config = load_config(profile, config)?;
+            let edits = RepertoireEdits::load(&config);
+            let db = OpeningDatabase::load_repertoire(&config.active().repertoire, &edits)?;
+            let history = DrillHistory::load(&config);
+            print!("{}", RepertoireStats::new(color, db.graph(color), &history));
+        }
+        Command::Lint { color } => {
+            let config = load_config(profile, config)?;
+            let edits = RepertoireEdits::load(&config);
+            let db = OpeningDatabase::load_repertoire(&config.active().repertoire, &edits)?;
+            let colors = match color {
+                Some(color) => vec![color],
+                None => Color::ALL.to_vec(),
+            };
+            let mut found = 0;
+            for color in colors {
+                let issues = lint(color, &db.prep(color));
+                println!("{} repertoire: {} problems", color, issues.len());
+                for issue in &issues {
+                    println!("{}", issue);
+                }
+                found += issues.len();
+            }
+            if found > 0 {
+                anyhow::bail!("Found {} problems in the repertoire", found);
+            }
+        }
+        Command::Audit {
+            color,
+            engine,
+            depth,
+            threshold,
+            annotate,
+            report,
+        } => {
+            let config = load_config(profile, config)?;
+            let settings = config.active().engine.clone();
+            let path = engine
+                .or_else(|| settings.as_ref().map(|x| x.path.clone()))
+                .ok_or_else(|| anyhow::anyhow!("No engine given or set in the config"))?;
+            let depth = depth.or(settings.as_ref().map(|x| x.depth)).unwrap_or(18);
+            let threshold = threshold
+                .or(settings.as_ref().map(|x| x.threshold))
+                .unwrap_or(50);
+
+            let mut edits = RepertoireEdits::load(&config);
+            let db = OpeningDatabase::load_repertoire(&config.active().repertoire, &edits)?;
+            let mut engine = UciEngine::new(&path)?;
+            let audit = audit(color, db.graph(color), &mut engine, depth, threshold)?;
+            print!("{}", audit);
+
+            let report =
+                report.unwrap_or_else(|| config.data_dir().join(format!("audit-{}.txt", color)));
+            fs::write(&report, audit.to_string())?;
+            println!("Report written to {}", report.display());
+            if annotate {
+                for edit in audit.annotations() {
+                    edits.push(color, edit);
+                }
+                edits.save(&config)?;
+                println!("Annotated {} moves", audit.flagged.len());
+            }
+        }
+        Command::ExportBook {
+            color,
+            output,
+            weights,
+        } => {
+            let config = load_config(profile, config)?;
+            let edits = RepertoireEdits::load(&config);
+            let db = OpeningDatabase::load_repertoire(&config.active().repertoire, &edits)?;
+            let entries = export_book(db.graph(color), weights);
+            write_book(&output, &entries)?;
+            println!("Wrote {} moves to {}", entries.len(), output.display());
+        }
+        Command::Scout {
+            opponent,
+            color,
+            months,
+            pgn,
+        } => {
+            let config = load_config(profile, config)?;
+            let edits = RepertoireEdits::load(&config);
+            let db = OpeningDatabase::load_repertoire(&config.active().repertoire, &edits)?;
+            let games = match pgn {
+                Some(pgn) => {
+                    OpeningDatabase::load_multigame_pgn(fs::File::open(pgn)?, opponent.clone())?
+                }
+                None => ChessComClient::new()?.download_player_games(&config, &opponent, months)?,
+            };
+            print!("{}", scout(&opponent, color, db.graph(color), &games));
+        }
+        Command::Export {
+            color,
+            moves,
+            format,
+            output,
+        } => {
+            let config = load_config(profile, config)?;
+            let edits = RepertoireEdits::load(&config);
+            let repertoire = OpeningDatabase::load_repertoire(&config.active().repertoire, &edits)?;
+            let db = export_database(&repertoire, Some(&load_cached_games(&config)));
+            let moves = moves.unwrap_or_default();
+            let graph = db.graph(color);
+            let text = match format {
+                ExportFormat::Dot => to_dot(graph, &color.to_string(), &moves)?,
+                ExportFormat::Json => serde_json::to_string_pretty(&to_tree(graph, &moves)?)?,
+            };
+            match output {
+                Some(output) => fs::write(output, text)?,
+                None => print!("{}", text),
+            }
+        }
+        Command::ImportMasters {
+            files,
+            plies,
+            min_rating,
+            output,
+        } => {
+            let output = match output {
+                Some(output) => output,
+                None => masters_path(&load_config(profile, config)?),
+            };
+            let settings = ImportSettings {
+                max_plies: plies,
+                min_rating,
+            };
+            let mut importer = MastersImporter::new(settings);
+            let pgns = files
+                .iter()
+                .map(|file| -> anyhow::Result<Box<dyn io::BufRead>> {
+                    if file == Path::new("-") {
+                        Ok(Box::new(io::stdin().lock()))
+                    } else {
+                        Ok(Box::new(io::BufReader::new(fs::File::open(file)?)))
+                    }
+                });
+            importer.add_pgns(pgns, threads())?;
+            if output.exists() {
+                importer.add_book(&MastersBook::load(&output)?);
+            }
+            let (book, summary) = importer.finish();
+            book.save(&output)?;
+            println!(
+                "Imported {} games, skipped {}. {} moves in {}",
+                summary.games,
+                summary.skipped,
+                book.len(),
+                output.display()
+            );
+        }
+    }
+    Ok(())
+}
@@ -6,13 +6,15 @@
 //! "https://api.chess.com/pub/player/$USER/games/$YEAR/$MONTH/pgn" year and month are numbers
 use crate::config::Config;
 use crate::db::OpeningDatabase;
+use crate::parallel::{parallel_map, threads};
 use anyhow::Context;
 use chrono::Datelike;
 use reqwest::blocking::*;
 use serde::Deserialize;
 use std::borrow::Cow;
 use std::fs;
-use tracing::{error, info};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
 
 #[derive(Clone)]
 pub struct ChessComClient {
@@ -25,19 +27,19 @@ pub struct Archives {
 }
 
 impl ChessComClient {
-    pub fn new() -> Self {
-        Self {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
             // So with the default user agent you trigger chess.com's security gateway and it sends
             // back an HTML page telling you that you look dangerous. It does trust curl though
             // with no caveats.
-            client: Client::builder().user_agent("curl/7.58.0").build().unwrap(),
-        }
+            client: Client::builder().user_agent("curl/7.58.0").build()?,
+        })
     }
 
     pub fn download_all_games(&self, config: &Config) -> anyhow::Result<OpeningDatabase> {
-        let mut db = OpeningDatabase::default();
+        let mut pgns = vec![];
         let chess_com_games = config.data_dir().join("chess.com");
-        for user in &config.chess_com {
+        for user in &config.active().chess_com {
             let archives = match self.get_user_archives(user) {
                 Ok(a) => a,
                 Err(e) => {
@@ -53,16 +55,7 @@ impl ChessComClient {
                 fs::create_dir_all(&user_folder).unwrap();
             }
             for (i, archive) in archives.iter().enumerate() {
-                let archive = if archive.ends_with("/pgn") {
-                    Cow::Borrowed(archive)
-                } else {
-                    let mut s = archive.to_string();
-                    if !s.ends_with("/") {
-                        s.push('/');
-                    }
-                    s.push_str("pgn");
-                    Cow::Owned(s)
-                };
+                let archive = pgn_url(archive);
                 info!("Processing archive: {}", archive);
                 let pgn = match self.download_pgn(archive.as_ref()) {
                     Ok(pgn) => pgn,
@@ -74,13 +67,83 @@ impl ChessComClient {
 
                 fs::write(user_folder.join(format!("{}.pgn", i)), pgn.as_bytes())
                     .context("Failed to cache in config dir")?;
+                pgns.push((pgn, user));
+            }
+        }
+        let mut db = OpeningDatabase::default();
+        let loaded = parallel_map(threads(), &pgns, |(pgn, user)| {
+            OpeningDatabase::load_multigame_pgn(pgn.as_bytes(), user.to_string())
+        });
+        for games in loaded {
+            match games {
+                Ok(games) => db.merge(&games),
+                Err(e) => error!("Failed to add to opening tree: {}", e),
+            }
+        }
+        Ok(db)
+    }
 
-                if let Err(e) = db.add_multigame_pgn(pgn.as_bytes(), user.to_string()) {
-                    error!("Failed to add to opening tree: {}", e);
+    /// Get the last `months` of someone else's chess.com games into their own database, like an
+    /// opponent we're preparing for. The PGNs are saved in the data directory under
+    /// `opponents/<user>` and months already there aren't downloaded again, apart from the latest
+    /// which may have new games. Months that fail to download are skipped, and if chess.com can't
+    /// be reached at all whatever was saved before is used.
+    pub fn download_player_games(
+        &self,
+        config: &Config,
+        user: &str,
+        months: usize,
+    ) -> anyhow::Result<OpeningDatabase> {
+        if !valid_username(user) {
+            anyhow::bail!("{:?} isn't a chess.com username", user);
+        }
+        let opponents = config.data_dir().join("opponents");
+        let folder = opponents.join(user);
+        let archives = match self.get_user_archives(user) {
+            Ok(archives) => archives,
+            Err(e) => {
+                let db = load_games_folder(&opponents, &[user.to_string()]);
+                if db.game_count() == 0 {
+                    return Err(e.context(format!("Couldn't get player archives for {}", user)));
                 }
+                warn!(
+                    "Couldn't get player archives for {}, using saved games: {}",
+                    user, e
+                );
+                return Ok(db);
+            }
+        };
+        fs::create_dir_all(&folder)?;
+        let mut files = vec![];
+        for (i, archive) in archives.iter().rev().take(months).enumerate() {
+            // Archives end with the year and month, named year first so they sort by date
+            let mut name = archive
+                .trim_end_matches('/')
+                .rsplit('/')
+                .take(2)
+                .collect::<Vec<_>>();
+            name.reverse();
+            let file = folder.join(format!("{}.pgn", name.join("-")));
+            if i > 0 && file.exists() {
+                files.push(file);
+                continue;
+            }
+            let url = pgn_url(archive);
+            info!("Downloading {}", url);
+            match self.download_pgn(&url) {
+                Ok(pgn) => {
+                    fs::write(&file, pgn.as_bytes()).context("Failed to cache opponent games")?;
+                }
+                Err(e) => warn!("Skipping {}: {}", url, e),
+            }
+            if file.exists() {
+                files.push(file);
             }
         }
-        Ok(db)
+        let user = user.to_string();
+        Ok(load_files(
+            &files.into_iter().map(|x| (x, &user)).collect::<Vec<_>>(),
+        ))
     }
 
     pub fn get_user_archives(&self, user: &str) -> anyhow::Result<Vec<String>> {
@@ -131,9 +194,110 @@ impl ChessComClient {
     }
 }
 
+/// Load the games already downloaded for the accounts in the config, this never goes online
+pub fn load_cached_games(config: &Config) -> OpeningDatabase {
+    load_games_folder(
+        &config.data_dir().join("chess.com"),
+        &config.active().chess_com,
+    )
+}
+
+/// Load the PGNs in the `folder/<user>` folder of each user, files that can't be read are skipped.
+/// The files are read in parallel.
+pub fn load_games_folder(folder: &Path, users: &[String]) -> OpeningDatabase {
+    let mut files = vec![];
+    for user in users {
+        let Ok(entries) = fs::read_dir(folder.join(user)) else {
+            info!("No downloaded games for {}", user);
+            continue;
+        };
+        let mut user_files = entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension().is_some_and(|x| x == "pgn"))
+            .collect::<Vec<_>>();
+        user_files.sort();
+        files.extend(user_files.into_iter().map(|x| (x, user)));
+    }
+    load_files(&files)
+}
+
+/// Load PGN files of games played by the given users in parallel, merged in order
+fn load_files(files: &[(PathBuf, &String)]) -> OpeningDatabase {
+    let loaded = parallel_map(threads(), files, |(file, user)| {
+        fs::File::open(file)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| OpeningDatabase::load_multigame_pgn(x, user.to_string()))
+    });
+    let mut db = OpeningDatabase::default();
+    for ((file, _), games) in files.iter().zip(loaded) {
+        match games {
+            Ok(games) => db.merge(&games),
+            Err(e) => error!("Couldn't load games from {}: {}", file.display(), e),
+        }
+    }
+    info!("Loaded {} downloaded games", db.game_count());
+    db
+}
+
+/// Usernames are used as folder names so only the characters chess.com allows are accepted
+fn valid_username(user: &str) -> bool {
+    !user.is_empty()
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// The archive list gives the URL for a month's games as JSON, the same with `/pgn` on the end is a
+/// PGN of them
+fn pgn_url(archive: &str) -> Cow<'_, str> {
+    if archive.ends_with("/pgn") {
+        Cow::Borrowed(archive)
+    } else {
+        let mut s = archive.to_string();
+        if !s.ends_with('/') {
+            s.push('/');
+        }
+        s.push_str("pgn");
+        Cow::Owned(s)
+    }
+}
+
 fn archive_url(user: &str, year: u16, month: u8) -> String {
     format!(
         "https://api.chess.com/pub/player/{}/games/{}/{}/pgn",
         user, year, month
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::Color;
+
+    #[test]
+    fn load_downloaded_games() {
+        let folder =
+            std::env::temp_dir().join(format!("chess-driller-games-{}", std::process::id()));
+        let user = folder.join("xd009642");
+        fs::create_dir_all(&user).unwrap();
+        fs::copy("tests/resources/games.pgn", user.join("0.pgn")).unwrap();
+        fs::write(user.join("notes.txt"), "not a pgn").unwrap();
+
+        let users = ["xd009642".to_string(), "nobody".to_string()];
+        let db = load_games_folder(&folder, &users);
+        assert_eq!(db.game_count(), 2);
+        assert!(db.graph(Color::White).node_count() > 0);
+        assert!(db.graph(Color::Black).node_count() > 0);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn usernames() {
+        assert!(valid_username("xd009642"));
+        assert!(valid_username("Some_User-1"));
+        assert!(!valid_username(""));
+        assert!(!valid_username("../config"));
+        assert!(!valid_username("a/b"));
+    }
+}
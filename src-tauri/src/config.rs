@@ -1,53 +1,767 @@
+use crate::drill::DrillConfig;
+use crate::store::save_json;
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shakmaty::Color;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
+use tracing::info;
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+/// The profile used when none has been picked, its data stays at the top of the data directory
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Version of the config format written by this build. Older configs are migrated when they're
+/// loaded: version 0 had a single profile at the top level and version 1 added profiles.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Environment variable with the path of the config file, `--config` is used over it
+pub const CONFIG_ENV: &str = "CHESS_DRILLER_CONFIG";
+
+/// Upgrades from each config version to the next
+const MIGRATIONS: [fn(Value) -> Value; CONFIG_VERSION as usize] = [migrate_v0, migrate_v1];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    pub version: u32,
+    /// The profile in use
+    pub profile: String,
+    pub profiles: BTreeMap<String, Profile>,
+    /// The file the config is saved to
+    #[serde(skip)]
+    path: PathBuf,
+    /// Settings replaced by environment variables, they're left out when the config is saved
+    #[serde(skip)]
+    overridden: Option<Box<Overridden>>,
+}
+
+/// Everything belonging to one person or repertoire. Each profile keeps its drill history and
+/// other data in its own folder.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
     /// Chess.com usernames for the user
     #[serde(rename = "chess.com")]
     pub chess_com: Vec<String>,
+    /// Folders of PGN files making up the user's repertoire
+    #[serde(default)]
+    pub repertoire: RepertoireFolders,
+    /// UCI engine used to check the repertoire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine: Option<EngineConfig>,
+    #[serde(default)]
+    pub drill: DrillConfig,
+}
+
+/// The top level of the config file, profiles are read one at a time so errors can say which
+/// profile they're in
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    version: u32,
+    profile: String,
+    profiles: BTreeMap<String, Value>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            profile: DEFAULT_PROFILE.to_string(),
+            profiles: BTreeMap::from([(DEFAULT_PROFILE.to_string(), Profile::default())]),
+            path: default_config_file(),
+            overridden: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineConfig {
+    pub path: PathBuf,
+    /// How deep the engine searches each position
+    #[serde(default = "default_depth")]
+    pub depth: u32,
+    /// Moves losing more centipawns than this compared to the engine's best move are flagged
+    #[serde(default = "default_threshold")]
+    pub threshold: i32,
+}
+
+fn default_depth() -> u32 {
+    18
+}
+
+fn default_threshold() -> i32 {
+    50
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepertoireFolders {
+    #[serde(default)]
+    pub white: Vec<PathBuf>,
+    #[serde(default)]
+    pub black: Vec<PathBuf>,
+}
+
+impl Default for RepertoireFolders {
+    fn default() -> Self {
+        Self::in_dir(&default_data_dir())
+    }
+}
+
+impl RepertoireFolders {
+    /// The `prep/white` and `prep/black` folders in a data directory
+    pub fn in_dir(data_dir: &Path) -> Self {
+        let prep = data_dir.join("prep");
+        Self {
+            white: vec![prep.join("white")],
+            black: vec![prep.join("black")],
+        }
+    }
+
+    pub fn folders(&self, color: Color) -> &[PathBuf] {
+        match color {
+            Color::White => &self.white,
+            Color::Black => &self.black,
+        }
+    }
+
+    pub fn folders_mut(&mut self, color: Color) -> &mut Vec<PathBuf> {
+        match color {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        }
+    }
+}
+
+/// Settings taken from `CHESS_DRILLER_*` environment variables. Lists of usernames are comma
+/// separated and lists of folders use the platform's path separator like `PATH`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    /// `CHESS_DRILLER_PROFILE`
+    pub profile: Option<String>,
+    /// `CHESS_DRILLER_CHESS_COM`
+    pub chess_com: Option<Vec<String>>,
+    /// `CHESS_DRILLER_WHITE`
+    pub white: Option<Vec<PathBuf>>,
+    /// `CHESS_DRILLER_BLACK`
+    pub black: Option<Vec<PathBuf>>,
+    /// `CHESS_DRILLER_ENGINE`
+    pub engine: Option<PathBuf>,
+}
+
+impl Overrides {
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var_os(name))
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<OsString>) -> Self {
+        let paths = |name| var(name).map(|x| env::split_paths(&x).collect());
+        Self {
+            profile: var("CHESS_DRILLER_PROFILE").map(|x| x.to_string_lossy().into_owned()),
+            chess_com: var("CHESS_DRILLER_CHESS_COM").map(|x| {
+                x.to_string_lossy()
+                    .split(',')
+                    .map(|x| x.trim().to_string())
+                    .filter(|x| !x.is_empty())
+                    .collect()
+            }),
+            white: paths("CHESS_DRILLER_WHITE"),
+            black: paths("CHESS_DRILLER_BLACK"),
+            engine: var("CHESS_DRILLER_ENGINE").map(PathBuf::from),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What environment variables replaced, so the file's own settings can be saved
+#[derive(Debug, Clone)]
+struct Overridden {
+    overrides: Overrides,
+    /// The profile in use in the file
+    file_profile: String,
+    /// The profile the overrides were applied to, as it was in the file
+    profile: String,
+    original: Profile,
+}
+
+fn default_config_file() -> PathBuf {
+    Config::config_dir().join("config.json")
+}
+
+fn default_data_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_default()
+        .join("chess-driller")
+        .join("data")
+}
+
+fn profile_data_dir(name: &str) -> PathBuf {
+    if name == DEFAULT_PROFILE {
+        default_data_dir()
+    } else {
+        default_data_dir().join("profiles").join(name)
+    }
+}
+
+/// Profile names are used for folder names so they're kept simple
+fn check_profile_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "Invalid profile name '{}', only letters, numbers, '-' and '_' can be used",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// The version of a config, those written before there was a version are worked out from their
+/// layout
+fn config_version(value: &Value) -> anyhow::Result<u32> {
+    let Some(object) = value.as_object() else {
+        bail!("The config should be a JSON object");
+    };
+    match object.get("version") {
+        Some(version) => version
+            .as_u64()
+            .and_then(|x| u32::try_from(x).ok())
+            .ok_or_else(|| anyhow::anyhow!("The config version should be a number")),
+        None if object.contains_key("profiles") => Ok(1),
+        None => Ok(0),
+    }
+}
+
+/// Version 0 is a single profile, it becomes the default profile
+fn migrate_v0(value: Value) -> Value {
+    serde_json::json!({
+        "profile": DEFAULT_PROFILE,
+        "profiles": { DEFAULT_PROFILE: value },
+    })
+}
+
+/// Version 1 just didn't have the version
+fn migrate_v1(mut value: Value) -> Value {
+    value["version"] = Value::from(2);
+    value
 }
 
 impl Config {
+    /// Load the config from the default location, see [`Config::load_from`]
     pub fn load() -> anyhow::Result<Self> {
-        let config_dir = dirs::config_dir().unwrap_or_default().join("chess-driller");
+        Self::load_from(None)
+    }
 
-        if !config_dir.exists() {
-            println!(
-                "Config file doesn't exist creating one in {}",
-                config_dir.display()
-            );
-            if let Err(e) = fs::create_dir_all(config_dir.join("data")) {
-                eprintln!("Couldn't create config dir: {}", e);
-                Ok(Self::default())
-            } else {
-                Ok(Self::create_default(&config_dir.join("config.json")))
+    /// Load the config from `path`, `CHESS_DRILLER_CONFIG` or the config directory, in that
+    /// order. A default config is written if the file doesn't exist, and older configs are
+    /// migrated with a copy of the original kept next to it. Environment overrides are applied.
+    pub fn load_from(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => env::var_os(CONFIG_ENV)
+                .map(PathBuf::from)
+                .unwrap_or_else(default_config_file),
+        };
+        let mut config = if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+            let (mut config, migrated) =
+                Self::parse(&data).with_context(|| format!("invalid config {}", path.display()))?;
+            config.path = path.clone();
+            if let Some(version) = migrated {
+                let backup = path.with_extension(format!("v{}.json", version));
+                fs::copy(&path, &backup)
+                    .with_context(|| format!("backing up the config to {}", backup.display()))?;
+                config.save()?;
+                info!(
+                    "Migrated {} from version {} to {}, the old config is in {}",
+                    path.display(),
+                    version,
+                    CONFIG_VERSION,
+                    backup.display()
+                );
             }
+            config
         } else {
-            let config_file = config_dir.join("config.json");
-            if let Ok(data) = fs::read(&config_file) {
-                let config = serde_json::from_slice(&data)?;
-                Ok(config)
-            } else {
-                Ok(Self::create_default(&config_file))
+            info!("Config file doesn't exist, creating {}", path.display());
+            let config = Self {
+                path,
+                ..Default::default()
+            };
+            config.save()?;
+            fs::create_dir_all(config.data_dir())?;
+            config
+        };
+        config.apply_overrides(Overrides::from_env())?;
+        Ok(config)
+    }
+
+    /// Read a config, migrating it if it's from an older version. The version it was migrated
+    /// from is returned with it.
+    pub fn parse(data: &[u8]) -> anyhow::Result<(Self, Option<u32>)> {
+        let mut value: Value =
+            serde_json::from_slice(data).context("The config isn't valid JSON")?;
+        let version = config_version(&value)?;
+        if version > CONFIG_VERSION {
+            bail!(
+                "The config is version {} but this version of chess-driller only supports up to {}",
+                version,
+                CONFIG_VERSION
+            );
+        }
+        for migration in &MIGRATIONS[version as usize..] {
+            value = migration(value);
+        }
+        let file: ConfigFile = serde_json::from_value(value)?;
+        let mut profiles = BTreeMap::new();
+        for (name, profile) in file.profiles {
+            let profile = serde_json::from_value(profile)
+                .with_context(|| format!("in profile '{}'", name))?;
+            profiles.insert(name, profile);
+        }
+        let config = Self {
+            version: file.version,
+            profile: file.profile,
+            profiles,
+            ..Default::default()
+        };
+        config.check()?;
+        Ok((config, (version < CONFIG_VERSION).then_some(version)))
+    }
+
+    /// Check the settings make sense, all the problems found are listed in the error
+    pub fn check(&self) -> anyhow::Result<()> {
+        let mut problems = vec![];
+        if self.version != CONFIG_VERSION {
+            problems.push(format!(
+                "version is {} but should be {}",
+                self.version, CONFIG_VERSION
+            ));
+        }
+        if !self.profiles.contains_key(&self.profile) {
+            problems.push(format!(
+                "the profile in use, '{}', isn't one of the profiles",
+                self.profile
+            ));
+        }
+        for (name, profile) in &self.profiles {
+            if let Err(e) = check_profile_name(name) {
+                problems.push(e.to_string());
+            }
+            for user in &profile.chess_com {
+                if user.is_empty() || user.contains(char::is_whitespace) {
+                    problems.push(format!(
+                        "profile '{}' has an invalid chess.com username '{}'",
+                        name, user
+                    ));
+                }
+            }
+            for color in Color::ALL {
+                let folders = profile.repertoire.folders(color);
+                if folders.iter().any(|x| x.as_os_str().is_empty()) {
+                    problems.push(format!(
+                        "profile '{}' has an empty {} repertoire folder",
+                        name, color
+                    ));
+                }
             }
+            if let Some(engine) = profile.engine.as_ref() {
+                if engine.path.as_os_str().is_empty() {
+                    problems.push(format!("profile '{}' has no engine path", name));
+                }
+                if engine.depth == 0 {
+                    problems.push(format!(
+                        "profile '{}' has an engine depth of 0, it has to be at least 1",
+                        name
+                    ));
+                }
+                if engine.threshold < 0 {
+                    problems.push(format!(
+                        "profile '{}' has a negative engine threshold",
+                        name
+                    ));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            bail!("Problems in the config:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// Replace settings with ones from the environment, they only apply to the profile in use
+    pub fn apply_overrides(&mut self, overrides: Overrides) -> anyhow::Result<()> {
+        if overrides.is_empty() {
+            return Ok(());
+        }
+        let file_profile = self.profile.clone();
+        if let Some(profile) = overrides.profile.as_ref() {
+            self.use_profile(profile)
+                .context("CHESS_DRILLER_PROFILE isn't valid")?;
+        }
+        let original = self.active().clone();
+        let profile = self.active_mut();
+        if let Some(chess_com) = overrides.chess_com.clone() {
+            profile.chess_com = chess_com;
+        }
+        if let Some(white) = overrides.white.clone() {
+            profile.repertoire.white = white;
+        }
+        if let Some(black) = overrides.black.clone() {
+            profile.repertoire.black = black;
+        }
+        if let Some(path) = overrides.engine.clone() {
+            match profile.engine.as_mut() {
+                Some(engine) => engine.path = path,
+                None => {
+                    profile.engine = Some(EngineConfig {
+                        path,
+                        depth: default_depth(),
+                        threshold: default_threshold(),
+                    })
+                }
+            }
+        }
+        info!("Applied settings from the environment: {:?}", overrides);
+        self.overridden = Some(Box::new(Overridden {
+            overrides,
+            file_profile,
+            profile: self.profile.clone(),
+            original,
+        }));
+        self.check()
+    }
+
+    fn check_overridden(&self, new: &Config) -> anyhow::Result<()> {
+        let Some(overridden) = self.overridden.as_ref() else {
+            return Ok(());
+        };
+        let (Some(old), Some(new)) = (
+            self.profiles.get(&overridden.profile),
+            new.profiles.get(&overridden.profile),
+        ) else {
+            return Ok(());
+        };
+        let overrides = &overridden.overrides;
+        let engine = |x: &Profile| x.engine.as_ref().map(|x| x.path.clone());
+        let mut fixed = vec![];
+        if overrides.chess_com.is_some() && old.chess_com != new.chess_com {
+            fixed.push("CHESS_DRILLER_CHESS_COM");
+        }
+        if overrides.white.is_some() && old.repertoire.white != new.repertoire.white {
+            fixed.push("CHESS_DRILLER_WHITE");
+        }
+        if overrides.black.is_some() && old.repertoire.black != new.repertoire.black {
+            fixed.push("CHESS_DRILLER_BLACK");
+        }
+        if overrides.engine.is_some() && engine(old) != engine(new) {
+            fixed.push("CHESS_DRILLER_ENGINE");
+        }
+        if !fixed.is_empty() {
+            bail!(
+                "Can't change settings given by {} in profile '{}'",
+                fixed.join(", "),
+                overridden.profile
+            );
         }
+        Ok(())
     }
 
-    fn create_default(save_dir: &Path) -> Self {
-        let res = Self::default();
-        let save = serde_json::to_vec_pretty(&res).unwrap();
-        if let Err(e) = fs::write(save_dir, &save) {
-            eprintln!("Failed to write out config file: {}", e);
+    /// The config as it's saved, without the settings from the environment
+    fn to_file(&self) -> Self {
+        let mut res = self.clone();
+        let Some(overridden) = self.overridden.as_ref() else {
+            return res;
+        };
+        let overrides = &overridden.overrides;
+        if overrides.profile.as_ref() == Some(&self.profile) {
+            res.profile = overridden.file_profile.clone();
+        }
+        if let Some(profile) = res.profiles.get_mut(&overridden.profile) {
+            let original = &overridden.original;
+            if overrides.chess_com.is_some() {
+                profile.chess_com = original.chess_com.clone();
+            }
+            if overrides.white.is_some() {
+                profile.repertoire.white = original.repertoire.white.clone();
+            }
+            if overrides.black.is_some() {
+                profile.repertoire.black = original.repertoire.black.clone();
+            }
+            if overrides.engine.is_some() {
+                profile.engine = original.engine.clone();
+            }
         }
         res
     }
 
+    /// Replace the settings with `new`, which is checked first. Where the config is saved and
+    /// the environment overrides are kept, so settings from the environment can't be changed as
+    /// the change would be lost when saving.
+    pub fn update(&mut self, new: Config) -> anyhow::Result<()> {
+        new.check()?;
+        self.check_overridden(&new)?;
+        self.version = new.version;
+        self.profile = new.profile;
+        self.profiles = new.profiles;
+        Ok(())
+    }
+
+    /// Save the config, it's written to a temporary file first and moved into place so a failed
+    /// write never leaves a broken config behind
+    pub fn save(&self) -> anyhow::Result<()> {
+        save_json(&self.path, &self.to_file())
+    }
+
+    pub fn config_dir() -> PathBuf {
+        dirs::config_dir().unwrap_or_default().join("chess-driller")
+    }
+
+    /// The file the config is saved to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the data for the profile in use is kept
     pub fn data_dir(&self) -> PathBuf {
-        dirs::config_dir()
-            .unwrap_or_default()
-            .join("chess-driller")
-            .join("data")
+        profile_data_dir(&self.profile)
+    }
+
+    /// The profile in use
+    pub fn active(&self) -> &Profile {
+        &self.profiles[&self.profile]
+    }
+
+    pub fn active_mut(&mut self) -> &mut Profile {
+        self.profiles.get_mut(&self.profile).unwrap()
+    }
+
+    /// Switch to another profile, it has to exist already
+    pub fn use_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        if !self.profiles.contains_key(name) {
+            let names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            bail!(
+                "There's no profile called '{}', the profiles are: {}",
+                name,
+                names.join(", ")
+            );
+        }
+        self.profile = name.to_string();
+        Ok(())
+    }
+
+    /// Add a new profile with its repertoire in its own data folder
+    pub fn add_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        check_profile_name(name)?;
+        if self.profiles.contains_key(name) {
+            bail!("There's already a profile called '{}'", name);
+        }
+        let profile = Profile {
+            repertoire: RepertoireFolders::in_dir(&profile_data_dir(name)),
+            ..Default::default()
+        };
+        self.profiles.insert(name.to_string(), profile);
+        Ok(())
+    }
+
+    /// Make sure all the repertoire folders exist. The default folders are created if they're
+    /// missing, for anything else the user has to fix their config.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let defaults = RepertoireFolders::in_dir(&self.data_dir());
+        let mut missing = vec![];
+        for color in Color::ALL {
+            for folder in self.active().repertoire.folders(color) {
+                if folder.is_dir() {
+                    continue;
+                }
+                if defaults.folders(color).contains(folder) {
+                    info!("Creating repertoire folder {}", folder.display());
+                    fs::create_dir_all(folder)?;
+                } else {
+                    missing.push(format!("{} ({})", folder.display(), color));
+                }
+            }
+        }
+        if !missing.is_empty() {
+            bail!(
+                "Repertoire folders don't exist: {}. Create them or remove them from {}",
+                missing.join(", "),
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(text: &str) -> anyhow::Result<(Config, Option<u32>)> {
+        Config::parse(text.as_bytes())
+    }
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn migrate_version_0() {
+        let (config, migrated) = parse(r#"{"chess.com": ["someone"]}"#).unwrap();
+        assert_eq!(migrated, Some(0));
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.profile, DEFAULT_PROFILE);
+        assert_eq!(config.active().chess_com, vec!["someone".to_string()]);
+        assert_eq!(config.active().repertoire, RepertoireFolders::default());
+        assert_eq!(config.active().drill, DrillConfig::default());
+
+        let text = r#"{
+            "chess.com": [],
+            "repertoire": {"white": ["w"], "black": []},
+            "engine": {"path": "/usr/bin/stockfish"}
+        }"#;
+        let (config, migrated) = parse(text).unwrap();
+        assert_eq!(migrated, Some(0));
+        assert_eq!(config.active().repertoire.white, vec![PathBuf::from("w")]);
+        assert_eq!(config.active().engine.as_ref().unwrap().depth, 18);
+    }
+
+    #[test]
+    fn migrate_version_1() {
+        let text = r#"{
+            "profile": "experimental",
+            "profiles": {
+                "default": {"chess.com": ["someone"]},
+                "experimental": {"chess.com": [], "drill": {"color_mode": "random"}}
+            }
+        }"#;
+        let (config, migrated) = parse(text).unwrap();
+        assert_eq!(migrated, Some(1));
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.profile, "experimental");
+        assert_eq!(config.profiles.len(), 2);
+
+        // Saved configs are read back as they are
+        let text = serde_json::to_string(&config).unwrap();
+        let (read, migrated) = parse(&text).unwrap();
+        assert_eq!(migrated, None);
+        assert_eq!(read.profile, config.profile);
+        assert_eq!(read.profiles, config.profiles);
+    }
+
+    #[test]
+    fn strict_validation() {
+        assert!(error("[]").contains("should be a JSON object"));
+        assert!(error(r#"{"version": 99}"#).contains("only supports up to 2"));
+        assert!(error(r#"{"chess.com": [], "engnie": {}}"#).contains("unknown field `engnie`"));
+        let unknown = error(
+            r#"{"version": 2, "profile": "a", "profiles": {"a": {"chess.com": [], "colour": 1}}}"#,
+        );
+        assert!(unknown.contains("in profile 'a'"));
+        assert!(unknown.contains("unknown field `colour`"));
+
+        let problems = error(
+            r#"{"version": 2, "profile": "missing", "profiles": {
+                "bad name": {"chess.com": ["two words"], "engine": {"path": "sf", "depth": 0}}
+            }}"#,
+        );
+        assert!(problems.contains("'missing', isn't one of the profiles"));
+        assert!(problems.contains("Invalid profile name 'bad name'"));
+        assert!(problems.contains("invalid chess.com username 'two words'"));
+        assert!(problems.contains("engine depth of 0"));
+    }
+
+    #[test]
+    fn environment_overrides() {
+        let vars = HashMap::from([
+            ("CHESS_DRILLER_PROFILE", "experimental"),
+            ("CHESS_DRILLER_CHESS_COM", "me, other,"),
+            ("CHESS_DRILLER_ENGINE", "/opt/stockfish"),
+        ]);
+        let overrides = Overrides::from_vars(|x| vars.get(x).map(OsString::from));
+        assert_eq!(overrides.white, None);
+        assert_eq!(
+            overrides.chess_com,
+            Some(vec!["me".to_string(), "other".to_string()])
+        );
+
+        let mut config = Config::default();
+        assert!(config.apply_overrides(overrides.clone()).is_err());
+        config.add_profile("experimental").unwrap();
+        config.apply_overrides(overrides).unwrap();
+        assert_eq!(config.profile, "experimental");
+        assert_eq!(config.active().chess_com.len(), 2);
+        let engine = config.active().engine.clone().unwrap();
+        assert_eq!(engine.path, PathBuf::from("/opt/stockfish"));
+
+        // Only the file's own settings are saved
+        let saved = config.to_file();
+        assert_eq!(saved.profile, DEFAULT_PROFILE);
+        let experimental = &saved.profiles["experimental"];
+        assert!(experimental.chess_com.is_empty());
+        assert_eq!(experimental.engine, None);
+
+        // Settings from the environment can't be changed, others can
+        let mut new = config.clone();
+        new.active_mut().chess_com = vec!["someone".to_string()];
+        let e = config.update(new).unwrap_err();
+        assert!(e.to_string().contains("CHESS_DRILLER_CHESS_COM"), "{}", e);
+        let mut new = config.clone();
+        new.active_mut().drill.opponent_view = true;
+        config.update(new).unwrap();
+        assert!(config.active().drill.opponent_view);
+        assert_eq!(config.active().chess_com.len(), 2);
+    }
+
+    #[test]
+    fn update_and_save() {
+        let dir = env::temp_dir().join(format!("chess-driller-config-{}", std::process::id()));
+        let path = dir.join("config.json");
+        let mut config = Config {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut new = Config::default();
+        new.active_mut().chess_com = vec!["not valid".to_string()];
+        assert!(config.update(new.clone()).is_err());
+        assert!(config.active().chess_com.is_empty());
+        new.active_mut().chess_com = vec!["someone".to_string()];
+        config.update(new).unwrap();
+        assert_eq!(config.path(), path);
+
+        config.save().unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+        let (read, _) = Config::parse(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(read.active().chess_com, vec!["someone".to_string()]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn profiles() {
+        let mut config = Config::default();
+        assert_eq!(config.data_dir(), default_data_dir());
+        assert!(config.use_profile("experimental").is_err());
+        assert!(config.add_profile("../escape").is_err());
+        config.add_profile("experimental").unwrap();
+        assert!(config.add_profile("experimental").is_err());
+        config.use_profile("experimental").unwrap();
+        let data_dir = default_data_dir().join("profiles").join("experimental");
+        assert_eq!(config.data_dir(), data_dir);
+        assert_eq!(
+            config.active().repertoire,
+            RepertoireFolders::in_dir(&data_dir)
+        );
+        assert!(config.check().is_ok());
     }
 }
@@ -1,10 +1,19 @@
 //! Store the opening preparation we want to work over - might rename it in future but it is kind
 //! of a mini stripped-down move database.
-use petgraph::graph::{Graph, NodeIndex};
+use crate::config::RepertoireFolders;
+use crate::edit::{apply_edits, RepertoireEdits};
+use crate::game::HintLevel;
+use crate::parallel::{parallel_map, threads};
+use crate::polyglot;
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
 use petgraph::Direction;
-use pgn_reader::{BufferedReader, SanPlus, Skip, Visitor};
-use shakmaty::Color;
-use std::path::Path;
+use pgn_reader::{BufferedReader, Nag, RawComment, RawHeader, SanPlus, Skip, Visitor};
+use serde::{Deserialize, Serialize};
+use shakmaty::fen::Fen;
+use shakmaty::{CastlingMode, Chess, Color, EnPassantMode, Position};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 use tracing::{error, info, warn};
 use walkdir::WalkDir;
@@ -19,12 +28,141 @@ pub enum MoveAssessment {
     PrepEnded,
 }
 
-pub type OpeningGraph = Graph<SanPlus, ()>;
+/// Whether a move is the one we want to play or just something else we know. Hints and the
+/// computer only pick alternatives when there's no primary move.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Primary,
+    Alternative,
+}
+
+/// A move in the opening tree along with any comments the PGN had for it. Kept small as game
+/// records can have millions of these, the details only prep has are boxed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpeningNode {
+    pub san: SanPlus,
+    pub priority: Priority,
+    /// Number of recorded games the move was played in, this is 0 for prep
+    pub games: u32,
+    details: Option<Box<NodeDetails>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeDetails {
+    pub comments: Vec<String>,
+    /// Indexes into the chapters for this colour that contain the move
+    pub chapters: Vec<usize>,
+    /// Annotations like `?!` from the PGN or an engine audit
+    pub nags: Vec<Nag>,
+    /// For the first move of a game set up with a `FEN` header, the position it's played from
+    pub start: Option<Fen>,
+}
+
+impl From<SanPlus> for OpeningNode {
+    fn from(san: SanPlus) -> Self {
+        Self {
+            san,
+            priority: Priority::Primary,
+            games: 0,
+            details: None,
+        }
+    }
+}
+
+impl OpeningNode {
+    pub fn comments(&self) -> &[String] {
+        self.details.as_ref().map_or(&[], |x| &x.comments)
+    }
+
+    pub fn chapters(&self) -> &[usize] {
+        self.details.as_ref().map_or(&[], |x| &x.chapters)
+    }
+
+    pub fn nags(&self) -> &[Nag] {
+        self.details.as_ref().map_or(&[], |x| &x.nags)
+    }
+
+    pub fn start(&self) -> Option<&Fen> {
+        self.details.as_ref().and_then(|x| x.start.as_ref())
+    }
+
+    /// The details to change, they're only allocated once something is set
+    pub fn details_mut(&mut self) -> &mut NodeDetails {
+        self.details.get_or_insert_with(Default::default)
+    }
+
+    /// The position a root move is played from
+    pub fn start_position(&self) -> Chess {
+        self.start()
+            .and_then(|x| setup_position(x).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// A single game from one of the prep files, for lichess studies this is a chapter.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Chapter {
+    pub file: PathBuf,
+    /// Taken from the `Event` header
+    pub name: String,
+    /// The `FEN` the chapter starts from if it doesn't start from the usual position
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+}
+
+/// Narrows a drill down to only part of the prep. Every filter set has to match for a move to be
+/// drilled, and the default filter drills everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrillFilter {
+    /// Only use moves from these prep files
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    /// Only use moves from chapters with this name
+    #[serde(default)]
+    pub chapter: Option<String>,
+    /// Only drill the lines that start with these moves
+    #[serde(default)]
+    pub moves: Vec<String>,
+    /// Drill the chapters set up from this FEN instead of the ones from the usual position
+    #[serde(default)]
+    pub start: Option<String>,
+}
+
+/// One colour's prep as read from its folders
+#[derive(Clone, Debug, Default)]
+pub struct Prep {
+    pub graph: OpeningGraph,
+    pub chapters: Vec<Chapter>,
+}
 
-#[derive(Default, Clone)]
+impl Prep {
+    /// Load all the PGNs and polyglot books in the folders, folders that don't exist are skipped
+    pub fn load(color: Color, folders: &[PathBuf]) -> anyhow::Result<Self> {
+        let existing = folders
+            .iter()
+            .filter(|x| {
+                if x.is_dir() {
+                    true
+                } else {
+                    error!("Repertoire folder {} doesn't exist", x.display());
+                    false
+                }
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let (graph, chapters) = load_folders(color, &existing, threads())?;
+        Ok(Self { graph, chapters })
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct OpeningDatabase {
     white_openings: OpeningGraph,
     black_openings: OpeningGraph,
+    white_chapters: Vec<Chapter>,
+    black_chapters: Vec<Chapter>,
 }
 
 #[derive(Clone)]
@@ -32,20 +170,69 @@ pub struct GameState {
     pub current_move: Option<NodeIndex>,
     player_turn: bool,
     still_running: bool,
+    /// The prep move we're currently giving hints towards and how much we've given away
+    hint: Option<(NodeIndex, HintLevel)>,
+    /// Every move the player made with the strongest hint they needed for it
+    player_moves: Vec<(NodeIndex, Option<HintLevel>)>,
+    went_wrong: bool,
+    /// If the drill is filtered these are the only moves we can play
+    allowed: Option<HashSet<NodeIndex>>,
+    /// Position the drill starts from, `None` for the usual starting position
+    start: Option<Box<Fen>>,
+    /// How often to pick each move when it's the computer's turn
+    weights: Option<HashMap<NodeIndex, u64>>,
 }
 
 impl OpeningDatabase {
-    pub fn load_default() -> anyhow::Result<Self> {
-        Self::load(Path::new("prep"))
+    /// Load the repertoire folders from the config with the edits made in the app applied,
+    /// folders that don't exist are skipped
+    pub fn load_repertoire(
+        folders: &RepertoireFolders,
+        edits: &RepertoireEdits,
+    ) -> anyhow::Result<Self> {
+        let mut this = Self::default();
+        for color in Color::ALL {
+            let mut prep = Prep::load(color, folders.folders(color))?;
+            apply_edits(&mut prep, color, edits.edits(color));
+            this.set_prep(color, prep);
+        }
+        Ok(this)
     }
 
+    /// A copy of the prep for one colour
+    pub fn prep(&self, player: Color) -> Prep {
+        Prep {
+            graph: self.graph(player).clone(),
+            chapters: self.chapters(player).to_vec(),
+        }
+    }
+
+    /// Replace the prep for one colour
+    pub fn set_prep(&mut self, player: Color, prep: Prep) {
+        match player {
+            Color::White => {
+                self.white_openings = prep.graph;
+                self.white_chapters = prep.chapters;
+            }
+            Color::Black => {
+                self.black_openings = prep.graph;
+                self.black_chapters = prep.chapters;
+            }
+        }
+    }
+
+    /// Load from a folder with a `white` and `black` subfolder
     pub fn load(root: &Path) -> anyhow::Result<Self> {
-        let white_openings = load_folder(&root.join("white"))?;
-        let black_openings = load_folder(&root.join("black"))?;
+        let (white_openings, white_chapters) =
+            load_folders(Color::White, &[root.join("white")], threads())?;
+        let (black_openings, black_chapters) =
+            load_folders(Color::Black, &[root.join("black")], threads())?;
 
         Ok(Self {
             white_openings,
             black_openings,
+            white_chapters,
+            black_chapters,
         })
     }
 
@@ -57,13 +244,85 @@ impl OpeningDatabase {
         }
     }
 
+    /// How many games were recorded, for databases made from played games
+    pub fn game_count(&self) -> u32 {
+        Color::ALL
+            .iter()
+            .map(|x| {
+                let graph = self.graph(*x);
+                graph
+                    .roots()
+                    .iter()
+                    .copied()
+                    .map(|n| graph[n].games)
+                    .sum::<u32>()
+            })
+            .sum()
+    }
+
+    pub fn chapters(&self, player: Color) -> &[Chapter] {
+        match player {
+            Color::White => &self.white_chapters,
+            _ => &self.black_chapters,
+        }
+    }
+
     pub fn start_drill(&self, player: Color, moves: &[SanPlus]) -> Option<GameState> {
-        let openings = self.graph(player);
-        let mut state = GameState {
-            player_turn: player == Color::White,
-            current_move: None,
-            still_running: true,
+        self.start_filtered_drill(player, moves, &DrillFilter::default())
+    }
+
+    /// Drill the chapters set up from the `fen` position
+    pub fn start_drill_from(
+        &self,
+        player: Color,
+        fen: &str,
+        moves: &[SanPlus],
+    ) -> Option<GameState> {
+        let filter = DrillFilter {
+            start: Some(fen.to_string()),
+            ..Default::default()
         };
+        self.start_filtered_drill(player, moves, &filter)
+    }
+
+    pub fn start_filtered_drill(
+        &self,
+        player: Color,
+        moves: &[SanPlus],
+        filter: &DrillFilter,
+    ) -> Option<GameState> {
+        self.start_drill_for_side(player, player, moves, filter)
+    }
+
+    /// Drill the moves for `side` using the `repertoire` prep, if these are different colours the
+    /// player is making the opponent's moves.
+    pub fn start_drill_for_side(
+        &self,
+        repertoire: Color,
+        side: Color,
+        moves: &[SanPlus],
+        filter: &DrillFilter,
+    ) -> Option<GameState> {
+        let openings = self.graph(repertoire);
+        let start = match filter.start_fen() {
+            Ok(start) => start,
+            Err(e) => {
+                error!("Invalid drill filter: {}", e);
+                return None;
+            }
+        };
+        let allowed = if filter == &DrillFilter::default() {
+            None
+        } else {
+            match self.filter_nodes(repertoire, filter) {
+                Ok(allowed) => Some(allowed),
+                Err(e) => {
+                    error!("Invalid drill filter: {}", e);
+                    return None;
+                }
+            }
+        };
+        let mut state = GameState::new(side, start.map(Box::new), allowed);
 
         for m in moves {
             let prep = state.apply_move(m, openings);
@@ -74,6 +333,82 @@ impl OpeningDatabase {
         Some(state)
     }
 
+    /// Drill a single line from the `repertoire` prep, like one returned by [`lines`], making the
+    /// moves for `side`
+    pub fn start_line_drill(
+        &self,
+        repertoire: Color,
+        side: Color,
+        line: &[NodeIndex],
+    ) -> GameState {
+        let start = line
+            .first()
+            .and_then(|x| self.graph(repertoire)[*x].start().cloned().map(Box::new));
+        GameState::new(side, start, Some(line.iter().copied().collect()))
+    }
+
+    /// Find all the moves a drill with this filter is allowed to use
+    fn filter_nodes(
+        &self,
+        player: Color,
+        filter: &DrillFilter,
+    ) -> anyhow::Result<HashSet<NodeIndex>> {
+        let openings = self.graph(player);
+        let start = filter.start_fen()?.map(|x| x.to_string());
+        let chapters = self
+            .chapters(player)
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| {
+                filter.files.is_empty() || filter.files.iter().any(|f| c.file.ends_with(f))
+            })
+            .filter(|(_, c)| {
+                filter
+                    .chapter
+                    .as_ref()
+                    .map(|n| *n == c.name)
+                    .unwrap_or(true)
+            })
+            .filter(|(_, c)| c.start == start)
+            .map(|(i, _)| i)
+            .collect::<HashSet<usize>>();
+        if chapters.is_empty() {
+            anyhow::bail!("no chapters match {:?}", filter);
+        }
+        let mut allowed = openings
+            .node_indices()
+            .filter(|n| openings[*n].chapters().iter().any(|c| chapters.contains(c)))
+            .collect::<HashSet<_>>();
+
+        if !filter.moves.is_empty() {
+            let mut subtree = HashSet::new();
+            let mut current: Option<NodeIndex> = None;
+            for mv in &filter.moves {
+                let san = SanPlus::from_ascii(mv.as_bytes())?;
+                let next = match current {
+                    Some(node) => openings
+                        .neighbors_directed(node, Direction::Outgoing)
+                        .find(|n| openings[*n].san == san),
+                    None => openings.roots().iter().copied().find(|n| {
+                        openings[*n].san == san
+                            && openings[*n].start().map(|x| x.to_string()) == start
+                    }),
+                };
+                let next = next.ok_or_else(|| anyhow::anyhow!("{} isn't in the prep", mv))?;
+                subtree.insert(next);
+                current = Some(next);
+            }
+            if let Some(last) = current {
+                let mut dfs = petgraph::visit::Dfs::new(&**openings, last);
+                while let Some(node) = dfs.next(&**openings) {
+                    subtree.insert(node);
+                }
+            }
+            allowed.retain(|n| subtree.contains(n));
+        }
+        Ok(allowed)
+    }
+
     pub fn load_multigame_pgn(pgns: impl io::Read, player: String) -> anyhow::Result<Self> {
         let mut this = Self::default();
         this.add_multigame_pgn(pgns, player)?;
@@ -99,9 +434,68 @@ impl OpeningDatabase {
         };
         Ok(())
     }
+
+    /// Add everything from `other` after what's already here, it's the same as if the PGNs it was
+    /// read from had been added to this database
+    pub fn merge(&mut self, other: &OpeningDatabase) {
+        let chapters = self.white_chapters.len();
+        merge_tree(&mut self.white_openings, &other.white_openings, chapters);
+        self.white_chapters.extend_from_slice(&other.white_chapters);
+        let chapters = self.black_chapters.len();
+        merge_tree(&mut self.black_openings, &other.black_openings, chapters);
+        self.black_chapters.extend_from_slice(&other.black_chapters);
+    }
+}
+
+impl DrillFilter {
+    /// The position the drill starts from if it isn't the usual one
+    pub fn start_fen(&self) -> anyhow::Result<Option<Fen>> {
+        match self.start.as_ref() {
+            Some(fen) => {
+                let fen = Fen::from_ascii(fen.as_bytes())?;
+                setup_position(&fen)?;
+                Ok(normalise_start(fen))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 impl GameState {
+    fn new(side: Color, start: Option<Box<Fen>>, allowed: Option<HashSet<NodeIndex>>) -> Self {
+        let turn = start.as_ref().map(|x| x.0.turn).unwrap_or(Color::White);
+        Self {
+            player_turn: side == turn,
+            current_move: None,
+            still_running: true,
+            hint: None,
+            player_moves: vec![],
+            went_wrong: false,
+            allowed,
+            start,
+            weights: None,
+        }
+    }
+
+    /// Pick the computer's moves in proportion to these weights, like how often an opponent plays
+    /// them. Moves without a weight are only picked if none of the moves have one.
+    pub fn set_move_weights(&mut self, weights: HashMap<NodeIndex, u64>) {
+        self.weights = Some(weights);
+    }
+
+    /// The position the drill starts from
+    pub fn start_position(&self) -> Chess {
+        self.start
+            .as_ref()
+            .and_then(|x| setup_position(x).ok())
+            .unwrap_or_default()
+    }
+
+    /// The FEN the drill starts from if it isn't the usual starting position
+    pub fn start_fen(&self) -> Option<&Fen> {
+        self.start.as_deref()
+    }
+
     pub fn still_running(&self) -> bool {
         self.still_running
     }
@@ -126,47 +520,39 @@ impl GameState {
         if !self.still_running {
             return None;
         }
-        let candidates = if let Some(index) = self.current_move {
-            openings
-                .neighbors_directed(index, Direction::Outgoing)
-                .collect::<Vec<NodeIndex>>()
-        } else {
-            self.find_roots(openings)
+        let candidates = self.candidates(openings);
+        let choice = match self
+            .weights
+            .as_ref()
+            .and_then(|x| weighted_choice(&candidates, x))
+        {
+            Some(choice) => choice,
+            None => *fastrand::choice(preferred(openings, candidates).iter())?,
         };
-        let choice = fastrand::choice(candidates.iter())?;
-        self.current_move = Some(*choice);
+        self.current_move = Some(choice);
         self.player_turn = !self.player_turn;
-        Some(openings[*choice].clone())
+        Some(openings[choice].san.clone())
     }
 
+    /// Check a move against the prep, once the drill has stopped every move is out of prep
     pub fn apply_move(&mut self, san: &SanPlus, openings: &OpeningGraph) -> MoveAssessment {
-        let mut has_neighbors = false;
-        let mut possible_moves = vec![];
-        if let Some(index) = self.current_move {
-            for next in openings.neighbors_directed(index, Direction::Outgoing) {
-                has_neighbors = true;
-                possible_moves.push(&openings[next]);
-                if &openings[next] == san {
-                    self.current_move = Some(next);
-                    self.player_turn = !self.player_turn;
-                    return MoveAssessment::InPrep;
-                }
-            }
-        } else {
-            for root in self.find_roots(openings).iter() {
-                has_neighbors = true;
-                possible_moves.push(&openings[*root]);
-                if &openings[*root] == san {
-                    self.current_move = Some(*root);
-                    self.player_turn = !self.player_turn;
-                    return MoveAssessment::InPrep;
-                }
+        if !self.still_running {
+            return MoveAssessment::OutOfPrep;
+        }
+        let candidates = self.candidates(openings);
+        let hint = self.hint.take().map(|(_, level)| level);
+        if let Some(next) = candidates.iter().find(|x| openings[**x].san == *san) {
+            if self.player_turn {
+                self.player_moves.push((*next, hint));
             }
+            self.current_move = Some(*next);
+            self.player_turn = !self.player_turn;
+            return MoveAssessment::InPrep;
         }
-        if has_neighbors {
-            let possible_moves = possible_moves
+        if !candidates.is_empty() {
+            let possible_moves = candidates
                 .iter()
-                .map(|x| x.to_string())
+                .map(|x| openings[*x].san.to_string())
                 .collect::<Vec<_>>()
                 .join(", ");
             info!(
@@ -174,6 +560,7 @@ impl GameState {
                 san, possible_moves
             );
             self.still_running = false;
+            self.went_wrong = self.player_turn;
             MoveAssessment::OutOfPrep
         } else {
             self.still_running = false;
@@ -185,52 +572,371 @@ impl GameState {
         self.player_turn
     }
 
-    fn find_roots(&self, openings: &OpeningGraph) -> Vec<NodeIndex> {
-        let mut res = vec![];
-        for node in openings.node_indices() {
-            if openings
-                .neighbors_directed(node, Direction::Incoming)
-                .count()
-                == 0
-            {
-                res.push(node);
+    /// Whether the player has made a move that wasn't in the prep
+    pub fn went_wrong(&self) -> bool {
+        self.went_wrong
+    }
+
+    /// Give the player a bit more help towards one of their prep moves. Each call reveals more
+    /// about the same move until there's nothing left to give away.
+    pub fn next_hint(&mut self, openings: &OpeningGraph) -> Option<(NodeIndex, HintLevel)> {
+        if !(self.still_running && self.player_turn) {
+            return None;
+        }
+        let hint = match self.hint {
+            Some((node, level)) => (node, level.next()),
+            None => {
+                let candidates = preferred(openings, self.candidates(openings));
+                (*fastrand::choice(candidates.iter())?, HintLevel::Piece)
             }
+        };
+        self.hint = Some(hint);
+        Some(hint)
+    }
+
+    /// The hint given for the player's current move if they've asked for one
+    pub fn current_hint(&self) -> Option<HintLevel> {
+        self.hint.map(|(_, level)| level)
+    }
+
+    /// Moves the player got right paired with the strongest hint used to find them.
+    pub fn player_moves(&self) -> &[(NodeIndex, Option<HintLevel>)] {
+        &self.player_moves
+    }
+
+    /// Score for the line drilled so far between 0 and 1. Every move found without help is worth
+    /// a full point, hints take away from that and going out of prep scores nothing.
+    pub fn review_score(&self) -> f32 {
+        let mut total: f32 = self
+            .player_moves
+            .iter()
+            .map(|(_, hint)| 1.0 - hint.map(HintLevel::penalty).unwrap_or(0.0))
+            .sum();
+        let mut attempts = self.player_moves.len();
+        if self.went_wrong {
+            attempts += 1;
+        }
+        if attempts == 0 {
+            total = 1.0;
+            attempts = 1;
+        }
+        total / attempts as f32
+    }
+
+    fn candidates(&self, openings: &OpeningGraph) -> Vec<NodeIndex> {
+        let mut candidates = if let Some(index) = self.current_move {
+            openings
+                .neighbors_directed(index, Direction::Outgoing)
+                .collect()
+        } else {
+            self.find_roots(openings)
+        };
+        if let Some(allowed) = self.allowed.as_ref() {
+            candidates.retain(|x| allowed.contains(x));
         }
-        res
+        candidates
+    }
+
+    fn find_roots(&self, openings: &OpeningGraph) -> Vec<NodeIndex> {
+        openings
+            .roots()
+            .iter()
+            .copied()
+            .filter(|x| openings[*x].start() == self.start.as_deref())
+            .collect()
     }
 }
 
-fn load_folder(folder: &Path) -> anyhow::Result<OpeningGraph> {
-    let mut graph = OpeningGraph::default();
-    for entry in WalkDir::new(folder)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
+/// Every line from a root to a leaf of the tree, main lines come before their variations.
+pub fn lines(openings: &OpeningGraph) -> Vec<Vec<NodeIndex>> {
+    let mut res = vec![];
+    let mut roots = openings.roots().to_vec();
+    roots.sort();
+    let mut stack = roots.into_iter().rev().map(|x| vec![x]).collect::<Vec<_>>();
+    while let Some(line) = stack.pop() {
+        let last = line[line.len() - 1];
+        let mut children = openings
+            .neighbors_directed(last, Direction::Outgoing)
+            .collect::<Vec<_>>();
+        if children.is_empty() {
+            res.push(line);
+            continue;
+        }
+        // Nodes are added in the order they're read so the lowest index is the main line
+        children.sort();
+        for child in children.into_iter().rev() {
+            let mut next = line.clone();
+            next.push(child);
+            stack.push(next);
+        }
+    }
+    res
+}
+
+/// Pick one of the candidates with a chance proportional to its weight
+fn weighted_choice(
+    candidates: &[NodeIndex],
+    weights: &HashMap<NodeIndex, u64>,
+) -> Option<NodeIndex> {
+    let weight = |x: &NodeIndex| weights.get(x).copied().unwrap_or(0);
+    let total = candidates.iter().map(weight).sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = fastrand::u64(0..total);
+    for candidate in candidates {
+        if pick < weight(candidate) {
+            return Some(*candidate);
+        }
+        pick -= weight(candidate);
+    }
+    None
+}
+
+/// Only the primary moves from `candidates` if there are any
+fn preferred(openings: &OpeningGraph, candidates: Vec<NodeIndex>) -> Vec<NodeIndex> {
+    let primary = candidates
+        .iter()
+        .copied()
+        .filter(|x| openings[*x].priority == Priority::Primary)
+        .collect::<Vec<_>>();
+    if primary.is_empty() {
+        candidates
+    } else {
+        primary
+    }
+}
+
+/// Set up the position from a FEN, castling rights are for standard chess
+pub fn setup_position(fen: &Fen) -> anyhow::Result<Chess> {
+    Ok(fen.clone().into_position(CastlingMode::Standard)?)
+}
+
+/// A FEN for the usual starting position is the same as not having one
+pub fn normalise_start(fen: Fen) -> Option<Fen> {
+    if fen == Fen::from_position(Chess::default(), EnPassantMode::Legal) {
+        None
+    } else {
+        Some(fen)
+    }
+}
+
+/// How many plies were played before a position, so moves from set up positions are numbered
+/// right
+pub fn ply_offset(position: &Chess) -> usize {
+    let ply = (position.fullmoves().get() as usize - 1) * 2;
+    match position.turn() {
+        Color::White => ply,
+        Color::Black => ply + 1,
+    }
+}
+
+/// Find the node at the end of a line of SAN moves starting from a root of the tree set up from
+/// `start`, or from the usual starting position if it's `None`
+pub fn find_line(
+    openings: &OpeningGraph,
+    start: Option<&Fen>,
+    moves: &[SanPlus],
+) -> Option<NodeIndex> {
+    let mut current: Option<NodeIndex> = None;
+    for san in moves {
+        current = Some(openings.find_move(current, san, start)?);
+    }
+    current
+}
+
+/// The line from a root of the tree to `node`
+pub fn path_to(openings: &OpeningGraph, node: NodeIndex) -> Vec<NodeIndex> {
+    let mut res = vec![node];
+    let mut current = node;
+    while let Some(parent) = openings
+        .neighbors_directed(current, Direction::Incoming)
+        .next()
     {
-        info!("Loading: {}", entry.path().display());
-        let load = fs::File::open(entry.path());
-        let load = match load {
-            Ok(s) => s,
+        res.push(parent);
+        current = parent;
+    }
+    res.reverse();
+    res
+}
+
+/// Moves in a line as SAN, this stays the same when the prep is reloaded unlike node indexes.
+/// Lines from a set up position start with its FEN and a `|`, so they're kept apart from lines
+/// with the same moves from somewhere else.
+pub fn line_key(openings: &OpeningGraph, line: &[NodeIndex]) -> String {
+    let start = line.first().and_then(|x| openings[*x].start());
+    moves_key(start, line.iter().map(|x| openings[*x].san.to_string()))
+}
+
+/// The key for moves from `start` like [`line_key`] gives, the key for no moves stands for the
+/// starting position itself
+pub fn moves_key(start: Option<&Fen>, moves: impl IntoIterator<Item = String>) -> String {
+    let mut key = start.map(|x| format!("{} |", x)).unwrap_or_default();
+    for san in moves {
+        if !key.is_empty() {
+            key.push(' ');
+        }
+        key.push_str(&san);
+    }
+    key
+}
+
+/// The starting position and moves in a key from [`line_key`]
+pub fn parse_line_key(key: &str) -> anyhow::Result<(Option<Fen>, Vec<SanPlus>)> {
+    let (start, moves) = match key.split_once('|') {
+        Some((fen, moves)) => (
+            normalise_start(Fen::from_ascii(fen.trim().as_bytes())?),
+            moves,
+        ),
+        None => (None, key),
+    };
+    let moves = moves
+        .split_whitespace()
+        .map(|x| SanPlus::from_ascii(x.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((start, moves))
+}
+
+/// Whether the line `key` goes through the position `prefix`, both keys from [`line_key`]
+pub fn key_starts_with(key: &str, prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return !key.contains('|');
+    }
+    key == prefix || key.strip_prefix(prefix).is_some_and(|x| x.starts_with(' '))
+}
+
+/// Every file in the folders is read on its own thread into its own tree, then the trees are
+/// merged in file order so the prep comes out the same however many threads there are.
+fn load_folders(
+    color: Color,
+    folders: &[PathBuf],
+    threads: usize,
+) -> anyhow::Result<(OpeningGraph, Vec<Chapter>)> {
+    let files = folders
+        .iter()
+        .flat_map(|folder| {
+            WalkDir::new(folder)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.into_path())
+        })
+        .collect::<Vec<_>>();
+    let loaded = parallel_map(threads, &files, |file| load_file(color, file));
+
+    let mut graph = OpeningGraph::default();
+    let mut chapters = vec![];
+    for file in loaded {
+        if let Some((tree, file_chapters)) = file? {
+            merge_tree(&mut graph, &tree, chapters.len());
+            chapters.extend(file_chapters);
+        }
+    }
+    Ok((graph, chapters))
+}
+
+/// Read one PGN or polyglot book from a repertoire folder, files that can't be opened are skipped
+fn load_file(color: Color, file: &Path) -> anyhow::Result<Option<(OpeningGraph, Vec<Chapter>)>> {
+    info!("Loading: {}", file.display());
+    if file.extension() == Some("bin".as_ref()) {
+        let mut book = match polyglot::read_book(file) {
+            Ok(book) => polyglot::book_graph(&book, color),
             Err(e) => {
-                error!("Failed to load {}. Error: {}", entry.path().display(), e);
-                continue;
+                error!("Failed to load {}. Error: {}", file.display(), e);
+                return Ok(None);
             }
         };
-        let mut reader = BufferedReader::new(load);
-        let mut pgn_visitor = PgnVisitor::new_with_graph(graph);
+        for node in book.node_indices() {
+            book[node].details_mut().chapters.push(0);
+        }
+        let chapter = Chapter {
+            file: file.to_path_buf(),
+            name: file
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            start: None,
+        };
+        return Ok(Some((book, vec![chapter])));
+    }
+    let load = match fs::File::open(file) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to load {}. Error: {}", file.display(), e);
+            return Ok(None);
+        }
+    };
+    let mut pgn_visitor = PgnVisitor::new_with_graph(OpeningGraph::default());
+    pgn_visitor.source = Some(file.to_path_buf());
+    let mut reader = BufferedReader::new(load);
+    while reader.has_more()? {
         reader.read_game(&mut pgn_visitor)?;
+    }
+    let graph = match pgn_visitor.pgn {
+        Pgn::Single { player } => player,
+        _ => unreachable!(),
+    };
+    Ok(Some((graph, pgn_visitor.chapters)))
+}
 
-        graph = match pgn_visitor.pgn {
-            Pgn::Single { player } => player,
-            _ => unreachable!(),
+/// Add the moves from `other` in the order they were added to it, so merging the trees read from
+/// several files gives the same graph as reading the files one after another. Its chapters are
+/// numbered after the `chapters` already loaded.
+fn merge_tree(openings: &mut OpeningGraph, other: &OpeningGraph, chapters: usize) {
+    // Parents are always added before their children
+    let mut new_index = Vec::with_capacity(other.node_count());
+    for node in other.node_indices() {
+        let parent = other
+            .neighbors_directed(node, Direction::Incoming)
+            .next()
+            .map(|x| new_index[x.index()]);
+        let from = &other[node];
+        let index = openings
+            .find_move(parent, &from.san, from.start())
+            .unwrap_or_else(|| {
+                let mut new = OpeningNode::from(from.san.clone());
+                new.priority = from.priority;
+                if let Some(start) = from.start() {
+                    new.details_mut().start = Some(start.clone());
+                }
+                openings.add_move(parent, new)
+            });
+        new_index.push(index);
+        openings[index].games += from.games;
+        if from.details.is_none() {
+            continue;
+        }
+        let details = openings[index].details_mut();
+        for chapter in from.chapters().iter().map(|x| x + chapters) {
+            if !details.chapters.contains(&chapter) {
+                details.chapters.push(chapter);
+            }
+        }
+        details.comments.extend_from_slice(from.comments());
+        for nag in from.nags() {
+            if !details.nags.contains(nag) {
+                details.nags.push(nag.clone());
+            }
         }
     }
+}
 
-    // debugging we can print the graphs and see they're right!
-    //let pretty_graph = graph.map(|_, node| node.to_string(), |_, _| ());
-    //let dot = petgraph::dot::Dot::new(&pretty_graph);
-    //println!("{:?}", dot);
-    Ok(graph)
+/// Lichess puts arrows and highlighted squares in comments as `[%cal ...]` and `[%csl ...]`,
+/// these aren't meant for reading so remove them.
+fn strip_commands(comment: &str) -> String {
+    let mut res = String::new();
+    let mut rest = comment;
+    while let Some(start) = rest.find("[%") {
+        res.push_str(&rest[..start]);
+        rest = match rest[start..].find(']') {
+            Some(end) => &rest[start + end + 1..],
+            None => "",
+        };
+    }
+    res.push_str(rest);
+    res.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
 #[derive(Debug)]
@@ -244,6 +950,19 @@ enum Pgn {
     },
 }
 
+impl Pgn {
+    fn graph_mut(&mut self, store_in_backup: bool) -> &mut OpeningGraph {
+        match self {
+            Pgn::Dual { black, .. } if store_in_backup => black,
+            Pgn::Single { .. } if store_in_backup => {
+                panic!("Trying to filter on player but no black graph!?")
+            }
+            Pgn::Single { player } => player,
+            Pgn::Dual { white, .. } => white,
+        }
+    }
+}
+
 impl Default for Pgn {
     fn default() -> Self {
         Self::Single {
@@ -255,21 +974,27 @@ impl Default for Pgn {
 #[derive(Default, Debug)]
 struct PgnVisitor {
     pgn: Pgn,
-    node_stack: Vec<NodeIndex>,
-    first: bool,
+    /// The node we're currently at for the main line and each variation we're in, `None` means
+    /// we're before the first move
+    node_stack: Vec<Option<NodeIndex>>,
     /// Used to show if we want to filter on player
     player: Option<String>,
     store_in_backup: bool,
+    /// File we're reading prep from, if set every game read becomes a chapter
+    source: Option<PathBuf>,
+    chapters: Vec<Chapter>,
+    /// The `FEN` and `SetUp` headers of the current game
+    fen: Option<Fen>,
+    setup: Option<bool>,
+    /// Where the current game starts if it's set up from a FEN
+    start: Option<Box<Fen>>,
 }
 
 impl PgnVisitor {
     pub fn new_with_graph(player: OpeningGraph) -> Self {
         Self {
             pgn: Pgn::Single { player },
-            node_stack: vec![],
-            first: true,
-            player: None,
-            store_in_backup: false,
+            ..Default::default()
         }
     }
 
@@ -277,9 +1002,7 @@ impl PgnVisitor {
         Self {
             pgn: Pgn::Dual { white, black },
             player: Some(player),
-            first: true,
-            node_stack: vec![],
-            store_in_backup: false,
+            ..Default::default()
         }
     }
 }
@@ -287,7 +1010,34 @@ impl PgnVisitor {
 impl Visitor for PgnVisitor {
     type Result = ();
 
-    fn header(&mut self, key: &[u8], value: pgn_reader::RawHeader) {
+    fn begin_game(&mut self) {
+        if let Some(file) = self.source.as_ref() {
+            self.chapters.push(Chapter {
+                file: file.clone(),
+                name: String::new(),
+                start: None,
+            });
+        }
+        self.fen = None;
+        self.setup = None;
+        self.start = None;
+        self.store_in_backup = false;
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader) {
+        if self.source.is_some() && key == b"Event" {
+            if let Some(chapter) = self.chapters.last_mut() {
+                chapter.name = value.decode_utf8_lossy().to_string();
+            }
+        }
+        match key {
+            b"FEN" => match Fen::from_ascii(value.as_bytes()) {
+                Ok(fen) => self.fen = Some(fen),
+                Err(e) => warn!("Invalid FEN {}: {}", value.decode_utf8_lossy(), e),
+            },
+            b"SetUp" => self.setup = Some(value.as_bytes() != b"0"),
+            _ => {}
+        }
         if let Some(player) = self.player.as_ref() {
             let color_key = match std::str::from_utf8(key) {
                 Ok("White") => Color::White,
@@ -296,85 +1046,101 @@ impl Visitor for PgnVisitor {
             };
 
             if let Ok(pgn_player) = std::str::from_utf8(value.0) {
-                if pgn_player == player {
+                // Usernames are typed in whatever case but sites keep the case it was made with
+                if pgn_player.eq_ignore_ascii_case(player) {
                     self.store_in_backup = color_key == Color::Black;
                 }
             }
         }
     }
 
+    fn end_headers(&mut self) -> Skip {
+        // Some tools write a FEN without `SetUp` but a `SetUp` of 0 says to ignore it
+        let Some(fen) = self.fen.take().filter(|_| self.setup != Some(false)) else {
+            return Skip(false);
+        };
+        if let Err(e) = setup_position(&fen) {
+            warn!("Skipping game with FEN {}: {}", fen, e);
+            return Skip(true);
+        }
+        self.start = normalise_start(fen).map(Box::new);
+        if self.source.is_some() {
+            if let Some(chapter) = self.chapters.last_mut() {
+                chapter.start = self.start.as_ref().map(|x| x.to_string());
+            }
+        }
+        Skip(false)
+    }
+
     fn end_game(&mut self) -> Self::Result {
         self.node_stack.clear();
-        self.first = true;
-        ()
     }
 
     fn san(&mut self, san_plus: SanPlus) {
-        let pgn = match &mut self.pgn {
-            Pgn::Dual { black, .. } if self.store_in_backup => black,
-            Pgn::Single { .. } if self.store_in_backup => {
-                panic!("Trying to filter on player but no black graph!?")
-            }
-            Pgn::Single { player } => player,
-            Pgn::Dual { white, .. } => white,
-        };
-        if self.first {
-            assert!(self.node_stack.is_empty());
-            self.first = false;
-            for node in pgn.node_indices() {
-                if pgn.neighbors_directed(node, Direction::Incoming).count() == 0 {
-                    if pgn[node] == san_plus {
-                        self.node_stack.push(node);
-                    }
-                }
+        let pgn = self.pgn.graph_mut(self.store_in_backup);
+        let parent = self.node_stack.last().copied().flatten();
+        // Games set up from different positions have their own trees
+        let existing = pgn.find_move(parent, &san_plus, self.start.as_deref());
+        let node = existing.unwrap_or_else(|| {
+            let mut node = OpeningNode::from(san_plus);
+            if parent.is_none() && self.start.is_some() {
+                node.details_mut().start = self.start.as_deref().cloned();
             }
-            if self.node_stack.is_empty() {
-                let node = pgn.add_node(san_plus);
-                self.node_stack.push(node);
+            pgn.add_move(parent, node)
+        });
+        if self.source.is_some() {
+            let chapter = self.chapters.len() - 1;
+            let chapters = &mut pgn[node].details_mut().chapters;
+            if !chapters.contains(&chapter) {
+                chapters.push(chapter);
             }
-        } else {
-            if let Some(old_node) = self.node_stack.last_mut() {
-                for neighbor in pgn.neighbors_directed(*old_node, Direction::Outgoing) {
-                    if pgn[neighbor] == san_plus {
-                        self.node_stack.push(neighbor);
-                        return;
-                    }
-                }
-                let node = pgn.add_node(san_plus);
-                pgn.add_edge(*old_node, node, ());
-                *old_node = node;
-            } else {
-                let node = pgn.add_node(san_plus);
-                self.node_stack.push(node);
+        }
+        if self.player.is_some() {
+            pgn[node].games += 1;
+        }
+        match self.node_stack.last_mut() {
+            Some(current) => *current = Some(node),
+            None => self.node_stack.push(Some(node)),
+        }
+    }
+
+    fn comment(&mut self, comment: RawComment<'_>) {
+        // Game records are full of clock times and engine evals, only keep the prep's comments
+        if self.player.is_some() {
+            return;
+        }
+        let comment = strip_commands(&String::from_utf8_lossy(comment.as_bytes()));
+        if comment.is_empty() {
+            return;
+        }
+        let pgn = self.pgn.graph_mut(self.store_in_backup);
+        if let Some(node) = self.node_stack.last().copied().flatten() {
+            pgn[node].details_mut().comments.push(comment);
+        }
+    }
+
+    fn nag(&mut self, nag: Nag) {
+        if self.player.is_some() {
+            return;
+        }
+        let pgn = self.pgn.graph_mut(self.store_in_backup);
+        if let Some(node) = self.node_stack.last().copied().flatten() {
+            let nags = &mut pgn[node].details_mut().nags;
+            if !nags.contains(&nag) {
+                nags.push(nag);
             }
         }
     }
 
     fn begin_variation(&mut self) -> Skip {
-        let pgn = match &mut self.pgn {
-            Pgn::Dual { black, .. } if self.store_in_backup => black,
-            Pgn::Single { .. } if self.store_in_backup => {
-                panic!("Trying to filter on player but no black graph!?")
-            }
-            Pgn::Single { player } => player,
-            Pgn::Dual { white, .. } => white,
-        };
-        // Variation is an alternative for last move pushed so we want to join to two moves back
-        // This won't work well with variations right at the start (should probably be
-        // `Vec<Option<NodeStack>>` to create new tree roots...
-        if let Some(last) = self.node_stack.last() {
-            let parents = pgn
-                .neighbors_directed(*last, Direction::Incoming)
-                .collect::<Vec<NodeIndex>>();
-            if parents.len() > 1 {
-                warn!("Unexpected extra parents!?");
-            }
-            if !parents.is_empty() {
-                self.node_stack.push(parents[0]);
-            }
-        } else {
-            warn!("No root?");
+        let pgn = self.pgn.graph_mut(self.store_in_backup);
+        // Variation is an alternative for the last move so we want to join to the move before it
+        let last = self.node_stack.last().copied().flatten();
+        if last.is_none() {
+            warn!("Variation before any moves");
         }
+        let parent = last.and_then(|x| pgn.neighbors_directed(x, Direction::Incoming).next());
+        self.node_stack.push(parent);
         Skip(false)
     }
 
@@ -388,6 +1154,65 @@ impl Visitor for PgnVisitor {
 mod tests {
     use super::*;
 
+    fn prep_files(folder: &str) -> Vec<PathBuf> {
+        let mut files = fs::read_dir(folder)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parallel_import() {
+        // Read one file after another through the same visitor
+        let folders = [PathBuf::from("prep/black"), PathBuf::from("prep/white")];
+        let mut visitor = PgnVisitor::new_with_graph(OpeningGraph::default());
+        for file in prep_files("prep/black")
+            .into_iter()
+            .chain(prep_files("prep/white"))
+        {
+            visitor.source = Some(file.clone());
+            let mut reader = BufferedReader::new(fs::File::open(file).unwrap());
+            while reader.has_more().unwrap() {
+                reader.read_game(&mut visitor).unwrap();
+            }
+        }
+        let Pgn::Single { player } = visitor.pgn else {
+            unreachable!()
+        };
+        let (graph, chapters) = load_folders(Color::Black, &folders, 4).unwrap();
+        assert_eq!(chapters, visitor.chapters);
+        assert_eq!(graph, player);
+
+        // Game records, with the prep thrown in for its variations
+        let games = fs::read_to_string("tests/resources/games.pgn").unwrap();
+        let (first, second) = games.split_at(games.rfind("[Event").unwrap());
+        let mut pgns = vec![first.to_string(), second.to_string()];
+        for file in prep_files("prep/black")
+            .into_iter()
+            .chain(prep_files("prep/white"))
+        {
+            pgns.push(fs::read_to_string(file).unwrap());
+        }
+        let pgns = pgns.iter().cycle().take(20).collect::<Vec<_>>();
+        let mut sequential = OpeningDatabase::default();
+        for pgn in &pgns {
+            sequential
+                .add_multigame_pgn(pgn.as_bytes(), "xd009642".to_string())
+                .unwrap();
+        }
+        let mut parallel = OpeningDatabase::default();
+        let loaded = parallel_map(4, &pgns, |pgn| {
+            OpeningDatabase::load_multigame_pgn(pgn.as_bytes(), "xd009642".to_string()).unwrap()
+        });
+        for games in loaded {
+            parallel.merge(&games);
+        }
+        assert!(sequential.game_count() > 0);
+        assert_eq!(parallel, sequential);
+    }
+
     #[test]
     fn load_multiline_pgn() {
         let load = fs::File::open("tests/resources/games.pgn");
@@ -421,4 +1246,188 @@ mod tests {
     fn load_test_prep() {
         OpeningDatabase::load(Path::new("prep")).unwrap();
     }
+
+    #[test]
+    fn filtered_drills() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::Black);
+        assert_eq!(db.chapters(Color::Black).len(), 3);
+
+        let filter = DrillFilter {
+            chapter: Some("Caro-Kann: Caro-Kann Advance Variation 1".to_string()),
+            ..Default::default()
+        };
+        let mut state = db.start_filtered_drill(Color::Black, &[], &filter).unwrap();
+        assert_eq!(state.make_move(graph).unwrap().to_string(), "e4");
+
+        let filter = DrillFilter {
+            moves: vec!["d4".to_string(), "d5".to_string(), "c4".to_string()],
+            ..Default::default()
+        };
+        let mut state = db.start_filtered_drill(Color::Black, &[], &filter).unwrap();
+        assert_eq!(state.make_move(graph).unwrap().to_string(), "d4");
+        let d5 = SanPlus::from_ascii(b"d5").unwrap();
+        assert_eq!(state.apply_move(&d5, graph), MoveAssessment::InPrep);
+        assert_eq!(state.make_move(graph).unwrap().to_string(), "c4");
+
+        let filter = DrillFilter {
+            files: vec![PathBuf::from("not_a_file.pgn")],
+            ..Default::default()
+        };
+        assert!(db
+            .start_filtered_drill(Color::Black, &[], &filter)
+            .is_none());
+    }
+
+    #[test]
+    fn opponent_view_drill() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::White);
+        let mut state = db
+            .start_drill_for_side(Color::White, Color::Black, &[], &DrillFilter::default())
+            .unwrap();
+        assert!(!state.is_player_turn());
+        assert_eq!(state.make_move(graph).unwrap().to_string(), "e4");
+        let e5 = SanPlus::from_ascii(b"e5").unwrap();
+        assert_eq!(state.apply_move(&e5, graph), MoveAssessment::InPrep);
+        assert_eq!(state.player_moves().len(), 1);
+    }
+
+    #[test]
+    fn large_weights() {
+        let candidates = [NodeIndex::new(0), NodeIndex::new(1)];
+        let weights = HashMap::from([
+            (candidates[0], u64::from(u32::MAX)),
+            (candidates[1], u64::MAX / 4),
+        ]);
+        for _ in 0..10 {
+            assert!(weighted_choice(&candidates, &weights).is_some());
+        }
+    }
+
+    #[test]
+    fn stopped_drills_ignore_moves() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::White);
+        let mut state = db.start_drill(Color::White, &[]).unwrap();
+        let a4 = SanPlus::from_ascii(b"a4").unwrap();
+        assert_eq!(state.apply_move(&a4, graph), MoveAssessment::OutOfPrep);
+        // e4 is in the prep from the start but the drill has already ended
+        let e4 = SanPlus::from_ascii(b"e4").unwrap();
+        assert_eq!(state.apply_move(&e4, graph), MoveAssessment::OutOfPrep);
+        assert!(state.player_moves().is_empty());
+        assert!(state.went_wrong());
+    }
+
+    #[test]
+    fn lines_in_order() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::White);
+        let all_lines = lines(graph);
+        assert_eq!(
+            all_lines.len(),
+            graph.externals(Direction::Outgoing).count()
+        );
+        assert_eq!(
+            line_key(graph, &all_lines[0]),
+            "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5 Qe2 Bf2+ Kd1 Nxc3+ bxc3 Bb6 a4"
+        );
+
+        let mut state = db.start_line_drill(Color::White, Color::White, &all_lines[0]);
+        for (i, node) in all_lines[0].iter().enumerate() {
+            if i % 2 == 0 {
+                assert_eq!(
+                    state.apply_move(&graph[*node].san, graph),
+                    MoveAssessment::InPrep
+                );
+            } else {
+                assert_eq!(state.make_move(graph).unwrap(), graph[*node].san);
+            }
+        }
+        assert!(!state.went_wrong());
+        assert_eq!(state.check_move(graph), MoveAssessment::PrepEnded);
+    }
+
+    #[test]
+    fn set_up_positions() {
+        let fen = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2";
+        let folder =
+            std::env::temp_dir().join(format!("chess-driller-setup-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let pgn = format!(
+            "[Event \"Reti\"]\n\n1. Nf3 d5 *\n\n\
+             [Event \"King's Knight\"]\n[SetUp \"1\"]\n[FEN \"{}\"]\n\n2. Nf3 Nc6 *\n",
+            fen
+        );
+        fs::write(folder.join("setup.pgn"), &pgn).unwrap();
+        let prep = Prep::load(Color::White, std::slice::from_ref(&folder)).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(prep.chapters[0].start, None);
+        assert_eq!(prep.chapters[1].start.as_deref(), Some(fen));
+        // Both start with Nf3 but from different positions so they aren't merged
+        assert_eq!(prep.graph.roots().iter().copied().count(), 2);
+        assert!(crate::lint::lint(Color::White, &prep).is_empty());
+
+        let mut db = OpeningDatabase::default();
+        db.set_prep(Color::White, prep);
+        let graph = db.graph(Color::White);
+        let nf3 = SanPlus::from_ascii(b"Nf3").unwrap();
+        for (state, reply) in [
+            (db.start_drill(Color::White, &[]), "d5"),
+            (db.start_drill_from(Color::White, fen, &[]), "Nc6"),
+        ] {
+            let mut state = state.unwrap();
+            assert!(state.is_player_turn());
+            assert_eq!(state.apply_move(&nf3, graph), MoveAssessment::InPrep);
+            assert_eq!(state.make_move(graph).unwrap().to_string(), reply);
+        }
+        let state = db.start_drill_from(Color::White, fen, &[]).unwrap();
+        assert_eq!(ply_offset(&state.start_position()), 2);
+
+        // Lines added from the position go in the game set up from it
+        let start = Fen::from_ascii(fen.as_bytes()).ok();
+        let line = [
+            nf3,
+            SanPlus::from_ascii(b"Nc6").unwrap(),
+            SanPlus::from_ascii(b"Bc4").unwrap(),
+        ];
+        let added = crate::pgn_edit::add_line(&pgn, start.as_ref(), &line, "Additions");
+        assert!(added.ends_with("2. Nf3 Nc6 3. Bc4 *\n"));
+        let added = crate::pgn_edit::add_line("", start.as_ref(), &line[..2], "Additions");
+        assert!(added.contains(&format!("[FEN \"{}\"]", fen)));
+        assert!(added.ends_with("2. Nf3 Nc6 *\n"));
+    }
+
+    #[test]
+    fn strip_comment_commands() {
+        assert_eq!(strip_commands("[%csl Rd4]"), "");
+        assert_eq!(
+            strip_commands("Good move [%cal Ge2e4,Gd2d4] for white"),
+            "Good move for white"
+        );
+    }
+
+    #[test]
+    fn hints_escalate() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::White);
+        let mut state = db.start_drill(Color::White, &[]).unwrap();
+
+        let (node, level) = state.next_hint(graph).unwrap();
+        assert_eq!(level, HintLevel::Piece);
+        assert_eq!(graph[node].san, SanPlus::from_ascii(b"e4").unwrap());
+        let levels = (0..4)
+            .map(|_| state.next_hint(graph).unwrap())
+            .collect::<Vec<_>>();
+        assert!(levels.iter().all(|(n, _)| *n == node));
+        assert_eq!(levels[0].1, HintLevel::Destination);
+        assert_eq!(levels[3].1, HintLevel::Comment);
+
+        let e4 = SanPlus::from_ascii(b"e4").unwrap();
+        assert_eq!(state.apply_move(&e4, graph), MoveAssessment::InPrep);
+        assert_eq!(state.player_moves(), &[(node, Some(HintLevel::Comment))]);
+        assert!(state.review_score() < 0.5);
+        // Not our move so no help
+        assert!(state.next_hint(graph).is_none());
+    }
 }
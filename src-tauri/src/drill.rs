@@ -0,0 +1,217 @@
+//! Settings for how each round of drilling is set up.
+use crate::config::Config;
+use crate::db::{line_key, lines};
+use crate::eco::Classifier;
+use crate::store::{load_json, save_json};
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+use std::collections::BTreeSet;
+use tracing::info;
+
+/// How we pick which repertoire the player is drilling each round
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    /// Always drill the colour picked with `reset`
+    #[default]
+    Fixed,
+    /// Pick a colour at random every round
+    Random,
+    /// Swap colours every round
+    Alternating,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DrillConfig {
+    #[serde(default)]
+    pub color_mode: ColorMode,
+    /// Have the player make the opponent's moves from our repertoire so they learn what replies
+    /// to expect
+    #[serde(default)]
+    pub opponent_view: bool,
+    /// Pick the computer's moves by how often strong players choose them, if a masters book has
+    /// been imported
+    #[serde(default)]
+    pub masters: bool,
+}
+
+impl DrillConfig {
+    /// Pick the repertoire for the next round given the one used last
+    pub fn next_repertoire(&self, last: Color) -> Color {
+        match self.color_mode {
+            ColorMode::Fixed => last,
+            ColorMode::Random => Color::from_white(fastrand::bool()),
+            ColorMode::Alternating => !last,
+        }
+    }
+
+    /// The side the player makes moves for when drilling a repertoire
+    pub fn side(&self, repertoire: Color) -> Color {
+        if self.opponent_view {
+            !repertoire
+        } else {
+            repertoire
+        }
+    }
+
+    /// The repertoire to use when the player is moving for `side`
+    pub fn repertoire(&self, side: Color) -> Color {
+        if self.opponent_view {
+            !side
+        } else {
+            side
+        }
+    }
+}
+
+/// The lines the player has already learned for each repertoire, saved in the data directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LearnProgress {
+    #[serde(default)]
+    white: BTreeSet<String>,
+    #[serde(default)]
+    black: BTreeSet<String>,
+}
+
+impl LearnProgress {
+    pub fn load(config: &Config) -> Self {
+        load_json(&config.data_dir().join("learned.json"))
+    }
+
+    pub fn save(&self, config: &Config) -> anyhow::Result<()> {
+        save_json(&config.data_dir().join("learned.json"), self)
+    }
+
+    pub fn learned(&self, color: Color) -> &BTreeSet<String> {
+        match color {
+            Color::White => &self.white,
+            Color::Black => &self.black,
+        }
+    }
+
+    pub fn learned_mut(&mut self, color: Color) -> &mut BTreeSet<String> {
+        match color {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LearnStatus {
+    /// Index of the line being learned, `None` once everything is learned
+    pub line: Option<usize>,
+    pub lines: usize,
+    pub completed: usize,
+    /// Percentage of lines learned
+    pub coverage: f32,
+    /// ECO code and name of the opening the current line ends in
+    pub opening: Option<String>,
+}
+
+/// Goes through every line of a repertoire in order. Each line is shown move by move before the
+/// player is quizzed on it, and lines are only done once they're played through without mistakes.
+#[derive(Clone, Debug)]
+pub struct LearnSession {
+    pub color: Color,
+    lines: Vec<Vec<NodeIndex>>,
+    /// The opening each line ends in
+    openings: Vec<Option<String>>,
+    completed: Vec<bool>,
+    current: Option<usize>,
+    /// How many moves of the current line have been shown
+    shown: usize,
+    /// Whether the player is being quizzed on the current line
+    quizzing: bool,
+}
+
+impl LearnSession {
+    pub fn new(color: Color, openings: &OpeningGraph, progress: &LearnProgress) -> Self {
+        let lines = lines(openings);
+        let learned = progress.learned(color);
+        let completed = lines
+            .iter()
+            .map(|x| learned.contains(&line_key(openings, x)))
+            .collect::<Vec<_>>();
+        let current = completed.iter().position(|x| !x);
+        let names = lines
+            .iter()
+            .map(|x| {
+                Classifier::bundled()
+                    .classify_node(openings, x[x.len() - 1])
+                    .map(|x| x.to_string())
+            })
+            .collect();
+        Self {
+            color,
+            lines,
+            openings: names,
+            completed,
+            current,
+            shown: 0,
+            quizzing: false,
+        }
+    }
+
+    pub fn current_line(&self) -> Option<&[NodeIndex]> {
+        self.current.map(|x| self.lines[x].as_slice())
+    }
+
+    /// The next move of the current line to show the player and how far into the line it is
+    pub fn show_next(&mut self) -> Option<(usize, NodeIndex)> {
+        let line = self.current_line()?;
+        let next = line.get(self.shown).copied()?;
+        self.shown += 1;
+        self.quizzing = false;
+        Some((self.shown - 1, next))
+    }
+
+    /// Start quizzing the player on the current line, if they want to see it again it will be
+    /// shown from the start
+    pub fn start_quiz(&mut self) {
+        self.shown = 0;
+        self.quizzing = self.current.is_some();
+    }
+
+    pub fn is_quizzing(&self) -> bool {
+        self.quizzing
+    }
+
+    /// Mark the current line as learned and move onto the next one we don't know
+    pub fn complete_line(&mut self, openings: &OpeningGraph, progress: &mut LearnProgress) {
+        if let Some(current) = self.current {
+            info!("Learned line {}", line_key(openings, &self.lines[current]));
+            self.completed[current] = true;
+            progress
+                .learned_mut(self.color)
+                .insert(line_key(openings, &self.lines[current]));
+            let next = self.completed[current..]
+                .iter()
+                .position(|x| !x)
+                .map(|x| x + current)
+                .or_else(|| self.completed.iter().position(|x| !x));
+            self.current = next;
+            self.shown = 0;
+            self.quizzing = false;
+        }
+    }
+
+    pub fn status(&self) -> LearnStatus {
+        let completed = self.completed.iter().filter(|x| **x).count();
+        let coverage = if self.lines.is_empty() {
+            100.0
+        } else {
+            100.0 * completed as f32 / self.lines.len() as f32
+        };
+        LearnStatus {
+            line: self.current,
+            lines: self.lines.len(),
+            completed,
+            coverage,
+            opening: self.current.and_then(|x| self.openings[x].clone()),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Name positions with their ECO code and opening name. Openings are read from TSV in the same
+//! format as the lichess chess-openings dataset, `resources/openings.tsv` is built into the app.
+use crate::db::path_to;
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use pgn_reader::SanPlus;
+use serde::Serialize;
+use shakmaty::fen::Epd;
+use shakmaty::{Chess, EnPassantMode, Position};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+use tracing::warn;
+
+const OPENINGS: &str = include_str!("../resources/openings.tsv");
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Opening {
+    pub eco: String,
+    pub name: String,
+}
+
+impl fmt::Display for Opening {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.eco, self.name)
+    }
+}
+
+/// Looks up openings by position so lines reaching them by transposition are named too
+#[derive(Clone, Debug, Default)]
+pub struct Classifier {
+    positions: HashMap<Epd, Opening>,
+}
+
+impl Classifier {
+    /// The openings built into the app
+    pub fn bundled() -> &'static Self {
+        static CLASSIFIER: OnceLock<Classifier> = OnceLock::new();
+        CLASSIFIER.get_or_init(|| Self::from_tsv(OPENINGS))
+    }
+
+    /// Read openings from TSV with `eco`, `name` and `pgn` columns. If more than one row reaches
+    /// the same position the first is used, and rows with illegal moves are skipped.
+    pub fn from_tsv(tsv: &str) -> Self {
+        let mut positions = HashMap::new();
+        for row in tsv.lines() {
+            let columns = row.split('\t').collect::<Vec<_>>();
+            if columns.len() < 3 || columns[0] == "eco" {
+                continue;
+            }
+            match play(columns[2]) {
+                Ok(position) => {
+                    positions
+                        .entry(Epd::from_position(position, EnPassantMode::Legal))
+                        .or_insert_with(|| Opening {
+                            eco: columns[0].to_string(),
+                            name: columns[1].to_string(),
+                        });
+                }
+                Err(e) => warn!("Skipping opening {}: {}", columns[1], e),
+            }
+        }
+        Self { positions }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// The opening if this exact position is a named one
+    pub fn classify_position(&self, position: &Chess) -> Option<&Opening> {
+        self.positions
+            .get(&Epd::from_position(position.clone(), EnPassantMode::Legal))
+    }
+
+    /// The last named opening reached playing `moves` from `start`
+    pub fn classify(&self, start: &Chess, moves: &[SanPlus]) -> Option<&Opening> {
+        let mut position = start.clone();
+        let mut res = self.classify_position(&position);
+        for san in moves {
+            let Ok(mv) = san.san.to_move(&position) else {
+                break;
+            };
+            position.play_unchecked(&mv);
+            if let Some(opening) = self.classify_position(&position) {
+                res = Some(opening);
+            }
+        }
+        res
+    }
+
+    /// Name the line in the graph ending at `node`
+    pub fn classify_node(&self, openings: &OpeningGraph, node: NodeIndex) -> Option<&Opening> {
+        let path = path_to(openings, node);
+        let moves = path
+            .iter()
+            .map(|x| openings[*x].san.clone())
+            .collect::<Vec<_>>();
+        self.classify(&openings[path[0]].start_position(), &moves)
+    }
+}
+
+/// Play out movetext like `1. e4 e5 2. Nf3` from the starting position
+fn play(pgn: &str) -> anyhow::Result<Chess> {
+    let mut position = Chess::default();
+    for token in pgn.split_whitespace() {
+        let token = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+        if token.is_empty() {
+            continue;
+        }
+        let mv = SanPlus::from_ascii(token.as_bytes())?
+            .san
+            .to_move(&position)?;
+        position.play_unchecked(&mv);
+    }
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{lines, OpeningDatabase};
+    use shakmaty::Color;
+    use std::path::Path;
+
+    fn sans(moves: &str) -> Vec<SanPlus> {
+        moves
+            .split_whitespace()
+            .map(|x| SanPlus::from_ascii(x.as_bytes()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn name_openings() {
+        let classifier = Classifier::bundled();
+        // Every bundled opening can be played and names a different position
+        assert_eq!(classifier.len(), OPENINGS.lines().count() - 1);
+        let start = Chess::default();
+        assert_eq!(classifier.classify(&start, &[]), None);
+
+        let najdorf = classifier
+            .classify(&start, &sans("e4 c5 Nf3 d6 d4 cxd4 Nxd4 Nf6 Nc3 a6 Be3"))
+            .unwrap();
+        assert_eq!(najdorf.eco, "B90");
+        assert_eq!(najdorf.name, "Sicilian Defense: Najdorf Variation");
+        // Transpositions get the name of the position
+        let qgd = classifier
+            .classify(&start, &sans("Nf3 d5 d4 Nf6 c4 e6 Nc3"))
+            .unwrap();
+        assert_eq!(
+            qgd.to_string(),
+            "D37 Queen's Gambit Declined: Three Knights Variation"
+        );
+
+        let custom =
+            Classifier::from_tsv("eco\tname\tpgn\nC25\tVienna\t1. e4 e5 2. Nc3\nX\tBad\t1. e5\n");
+        assert_eq!(custom.len(), 1);
+
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::Black);
+        let line = &lines(graph)[0];
+        let opening = classifier
+            .classify_node(graph, line[line.len() - 1])
+            .unwrap();
+        assert_eq!(opening.eco, "B12");
+    }
+}
@@ -0,0 +1,258 @@
+//! Changes made to the repertoire from inside the app. The PGN files are left alone, instead the
+//! edits are saved in the data directory and replayed every time the prep is loaded.
+use crate::config::Config;
+use crate::db::{find_line, parse_line_key, path_to, Prep, Priority};
+use crate::store::{load_json, save_json};
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::Dfs;
+use petgraph::Direction;
+use pgn_reader::Nag;
+use serde::{Deserialize, Serialize};
+use shakmaty::{Color, Position};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Lines are the SAN moves from the start of the game separated by spaces, like `e4 e5 Nf3`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Edit {
+    /// Remove the last move of the line and everything after it
+    Prune { line: String },
+    /// Set the priority of the last move of the line, this has to be a move for our side
+    SetPriority { line: String, priority: Priority },
+    /// Make the line the main line everywhere it's currently a variation
+    Promote { line: String },
+    /// Replace the comments on the last move of the line
+    Annotate { line: String, comments: Vec<String> },
+    /// Replace the NAGs on the last move of the line, these are the numbers in `$2`
+    SetNags { line: String, nags: Vec<u8> },
+    /// Rename the chapters in `file` called `chapter`
+    RenameChapter {
+        file: PathBuf,
+        chapter: String,
+        name: String,
+    },
+}
+
+impl Edit {
+    pub fn apply(&self, color: Color, prep: &mut Prep) -> anyhow::Result<()> {
+        match self {
+            Self::Prune { line } => {
+                let node = line_node(&prep.graph, line)?;
+                let mut removed = HashSet::new();
+                let mut dfs = Dfs::new(&*prep.graph, node);
+                while let Some(node) = dfs.next(&*prep.graph) {
+                    removed.insert(node);
+                }
+                // Keeps the nodes in the same order so main lines stay main lines
+                prep.graph = OpeningGraph::from(prep.graph.filter_map(
+                    |n, node| (!removed.contains(&n)).then(|| node.clone()),
+                    |_, _| Some(()),
+                ));
+            }
+            Self::SetPriority { line, priority } => {
+                let node = line_node(&prep.graph, line)?;
+                let path = path_to(&prep.graph, node);
+                let first = prep.graph[path[0]].start_position().turn();
+                // Sides take turns from whoever moves first in the line
+                let last = if path.len() % 2 == 1 { first } else { !first };
+                if last != color {
+                    anyhow::bail!("{} ends on the opponent's move", line);
+                }
+                prep.graph[node].priority = *priority;
+            }
+            Self::Promote { line } => {
+                let node = line_node(&prep.graph, line)?;
+                let first = path_to(&prep.graph, node)
+                    .into_iter()
+                    .collect::<HashSet<_>>();
+                prep.graph = reorder(&prep.graph, &first);
+            }
+            Self::Annotate { line, comments } => {
+                let node = line_node(&prep.graph, line)?;
+                prep.graph[node].details_mut().comments = comments.clone();
+            }
+            Self::SetNags { line, nags } => {
+                let node = line_node(&prep.graph, line)?;
+                prep.graph[node].details_mut().nags = nags.iter().map(|x| Nag(*x)).collect();
+            }
+            Self::RenameChapter {
+                file,
+                chapter,
+                name,
+            } => {
+                let mut found = false;
+                for c in prep
+                    .chapters
+                    .iter_mut()
+                    .filter(|c| c.file == *file && c.name == *chapter)
+                {
+                    c.name = name.clone();
+                    found = true;
+                }
+                if !found {
+                    anyhow::bail!("No chapter {} in {}", chapter, file.display());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply edits in the order they were made, any that no longer make sense because the PGNs have
+/// changed are skipped.
+pub fn apply_edits(prep: &mut Prep, color: Color, edits: &[Edit]) {
+    for edit in edits {
+        if let Err(e) = edit.apply(color, prep) {
+            warn!("Skipping {:?}: {}", edit, e);
+        }
+    }
+}
+
+fn line_node(openings: &OpeningGraph, line: &str) -> anyhow::Result<NodeIndex> {
+    let (start, moves) = parse_line_key(line)?;
+    find_line(openings, start.as_ref(), &moves)
+        .ok_or_else(|| anyhow::anyhow!("{} isn't in the prep", line))
+}
+
+/// Rebuild the graph so the nodes in `first` come before their siblings. As the main line is the
+/// child with the lowest index, the nodes are added in the order [`crate::db::lines`] walks them.
+fn reorder(openings: &OpeningGraph, first: &HashSet<NodeIndex>) -> OpeningGraph {
+    let mut res = OpeningGraph::default();
+    let mut new_index = HashMap::new();
+    let key = |n: &NodeIndex| (!first.contains(n), *n);
+
+    let mut roots = openings.roots().to_vec();
+    roots.sort_by_key(key);
+    let mut stack = roots.into_iter().rev().collect::<Vec<_>>();
+    while let Some(node) = stack.pop() {
+        let parent = openings
+            .neighbors_directed(node, Direction::Incoming)
+            .next();
+        let index = res.add_move(parent.map(|x| new_index[&x]), openings[node].clone());
+        new_index.insert(node, index);
+        let mut children = openings
+            .neighbors_directed(node, Direction::Outgoing)
+            .collect::<Vec<_>>();
+        children.sort_by_key(key);
+        stack.extend(children.into_iter().rev());
+    }
+    res
+}
+
+/// Every edit made to each repertoire, saved in the data directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepertoireEdits {
+    #[serde(default)]
+    white: Vec<Edit>,
+    #[serde(default)]
+    black: Vec<Edit>,
+}
+
+impl RepertoireEdits {
+    pub fn load(config: &Config) -> Self {
+        load_json(&config.data_dir().join("edits.json"))
+    }
+
+    pub fn save(&self, config: &Config) -> anyhow::Result<()> {
+        save_json(&config.data_dir().join("edits.json"), self)
+    }
+
+    pub fn edits(&self, color: Color) -> &[Edit] {
+        match color {
+            Color::White => &self.white,
+            Color::Black => &self.black,
+        }
+    }
+
+    pub fn push(&mut self, color: Color, edit: Edit) {
+        match color {
+            Color::White => self.white.push(edit),
+            Color::Black => self.black.push(edit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{line_key, lines, MoveAssessment, OpeningDatabase};
+    use pgn_reader::SanPlus;
+    use std::path::Path;
+
+    fn white_prep() -> Prep {
+        OpeningDatabase::load(Path::new("prep"))
+            .unwrap()
+            .prep(Color::White)
+    }
+
+    fn line_keys(prep: &Prep) -> Vec<String> {
+        lines(&prep.graph)
+            .iter()
+            .map(|x| line_key(&prep.graph, x))
+            .collect()
+    }
+
+    #[test]
+    fn prune_and_promote() {
+        let mut prep = white_prep();
+        let before = line_keys(&prep);
+        let variation = before[1].clone();
+
+        let promote = Edit::Promote {
+            line: variation.clone(),
+        };
+        promote.apply(Color::White, &mut prep).unwrap();
+        let promoted = line_keys(&prep);
+        assert_eq!(promoted[0], variation);
+        assert_eq!(promoted.len(), before.len());
+
+        let prune = Edit::Prune {
+            line: "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5 d4".to_string(),
+        };
+        prune.apply(Color::White, &mut prep).unwrap();
+        let pruned = line_keys(&prep);
+        assert!(pruned.len() < before.len());
+        assert!(pruned.iter().all(|x| !x.contains("Bc5 d4")));
+        assert!(prune.apply(Color::White, &mut prep).is_err());
+    }
+
+    #[test]
+    fn priorities() {
+        let mut prep = white_prep();
+        // Only our moves can be alternatives
+        let edit = Edit::SetPriority {
+            line: "e4 e5".to_string(),
+            priority: Priority::Alternative,
+        };
+        assert!(edit.apply(Color::White, &mut prep).is_err());
+
+        let line = "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5 Qe2";
+        let edit = Edit::SetPriority {
+            line: line.to_string(),
+            priority: Priority::Alternative,
+        };
+        edit.apply(Color::White, &mut prep).unwrap();
+
+        let mut db = OpeningDatabase::default();
+        db.set_prep(Color::White, prep);
+        let moves = "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5"
+            .split_whitespace()
+            .map(|x| SanPlus::from_ascii(x.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        for _ in 0..10 {
+            let mut state = db.start_drill(Color::White, &moves).unwrap();
+            let (node, _) = state.next_hint(db.graph(Color::White)).unwrap();
+            assert_eq!(db.graph(Color::White)[node].san.to_string(), "d4");
+        }
+        // The alternative is still in the prep
+        let mut state = db.start_drill(Color::White, &moves).unwrap();
+        let qe2 = SanPlus::from_ascii(b"Qe2").unwrap();
+        assert_eq!(
+            state.apply_move(&qe2, db.graph(Color::White)),
+            MoveAssessment::InPrep
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Talk to a UCI engine like stockfish so we can check the prep against it.
+use anyhow::Context;
+use shakmaty::fen::Fen;
+use shakmaty::uci::Uci;
+use shakmaty::{CastlingMode, Chess, EnPassantMode, Position};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use tracing::{debug, warn};
+
+/// Mates are scored as this many centipawns minus the number of moves to mate
+const MATE_SCORE: i32 = 100_000;
+
+/// What the engine thinks of a position from the point of view of the side to move
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Evaluation {
+    pub best: Option<Uci>,
+    pub centipawns: i32,
+}
+
+pub trait Engine {
+    fn evaluate(&mut self, position: &Chess, depth: u32) -> anyhow::Result<Evaluation>;
+}
+
+pub struct UciEngine {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl UciEngine {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("starting engine {}", path.display()))?;
+        let stdin = child.stdin.take().context("no engine stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("no engine stdout")?);
+        let mut this = Self {
+            child,
+            stdin,
+            stdout,
+        };
+        this.send("uci")?;
+        this.wait_for("uciok")?;
+        this.send("isready")?;
+        this.wait_for("readyok")?;
+        Ok(this)
+    }
+
+    fn send(&mut self, command: &str) -> anyhow::Result<()> {
+        debug!("Engine <- {}", command);
+        writeln!(self.stdin, "{}", command)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            anyhow::bail!("Engine closed its output");
+        }
+        Ok(line.trim().to_string())
+    }
+
+    fn wait_for(&mut self, response: &str) -> anyhow::Result<()> {
+        while self.read_line()? != response {}
+        Ok(())
+    }
+}
+
+impl Engine for UciEngine {
+    fn evaluate(&mut self, position: &Chess, depth: u32) -> anyhow::Result<Evaluation> {
+        let fen = Fen::from_position(position.clone(), EnPassantMode::Legal);
+        self.send(&format!("position fen {}", fen))?;
+        self.send(&format!("go depth {}", depth))?;
+        let mut centipawns = None;
+        loop {
+            let line = self.read_line()?;
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("info") => {
+                    if let Some(score) = parse_score(&line) {
+                        centipawns = Some(score);
+                    }
+                }
+                Some("bestmove") => {
+                    let best = words
+                        .next()
+                        .filter(|x| *x != "(none)")
+                        .and_then(|x| x.parse::<Uci>().ok());
+                    // No legal moves so there's no search, score it ourselves
+                    let centipawns = match centipawns {
+                        Some(cp) => cp,
+                        None if position.is_checkmate() => -MATE_SCORE,
+                        None => 0,
+                    };
+                    return Ok(Evaluation { best, centipawns });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        if self.send("quit").is_err() || self.child.wait().is_err() {
+            warn!("Engine didn't shut down cleanly");
+            let _ = self.child.kill();
+        }
+    }
+}
+
+/// Get the score out of an `info` line, only the main line is used if there's multipv output
+fn parse_score(line: &str) -> Option<i32> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    if let Some(i) = words.iter().position(|x| *x == "multipv") {
+        if words.get(i + 1) != Some(&"1") {
+            return None;
+        }
+    }
+    let i = words.iter().position(|x| *x == "score")?;
+    let value = words.get(i + 2)?.parse::<i32>().ok()?;
+    match *words.get(i + 1)? {
+        "cp" => Some(value),
+        "mate" if value > 0 => Some(MATE_SCORE - value),
+        "mate" => Some(-MATE_SCORE - value),
+        _ => None,
+    }
+}
+
+/// The move in UCI notation, as engines write it
+pub fn to_uci(mv: &shakmaty::Move) -> Uci {
+    mv.to_uci(CastlingMode::Standard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores() {
+        assert_eq!(
+            parse_score("info depth 10 seldepth 14 multipv 1 score cp -35 nodes 100 pv e7e5"),
+            Some(-35)
+        );
+        assert_eq!(
+            parse_score("info depth 20 score mate 3 pv d1h5"),
+            Some(MATE_SCORE - 3)
+        );
+        assert_eq!(
+            parse_score("info depth 20 score mate -2 pv d1h5"),
+            Some(-MATE_SCORE + 2)
+        );
+        assert_eq!(parse_score("info depth 10 multipv 2 score cp 20"), None);
+        assert_eq!(parse_score("info string NNUE enabled"), None);
+    }
+}
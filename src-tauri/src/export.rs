@@ -0,0 +1,196 @@
+//! Write out the opening graph for looking at in other tools, either as DOT for GraphViz or as a
+//! nested JSON tree.
+use crate::db::{
+    find_line, parse_line_key, path_to, ply_offset, OpeningDatabase, OpeningNode, Priority,
+};
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Moves from the prep files, anything else came from game records
+const PREP_COLOUR: &str = "lightblue";
+const GAME_COLOUR: &str = "lightgrey";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Dot,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TreeNode {
+    pub san: String,
+    /// Number of the player's games the move was played in
+    pub frequency: u32,
+    /// Whether the move is in the repertoire rather than only in games
+    pub in_prep: bool,
+    pub priority: Priority,
+    pub comments: Vec<String>,
+    pub children: Vec<TreeNode>,
+}
+
+/// The repertoire with the player's games added, so the export shows how often each move was
+/// played and where the games left the prep
+pub fn export_database(
+    repertoire: &OpeningDatabase,
+    games: Option<&OpeningDatabase>,
+) -> OpeningDatabase {
+    let mut db = repertoire.clone();
+    if let Some(games) = games {
+        db.merge(games);
+    }
+    db
+}
+
+fn in_prep(node: &OpeningNode) -> bool {
+    !node.chapters().is_empty()
+}
+
+/// The roots of the tree to export, either every root or the end of `line`, a key like
+/// [`line_key`](crate::db::line_key) gives. The number of plies before each root is returned
+/// with it so moves can be numbered.
+fn export_roots(openings: &OpeningGraph, line: &str) -> anyhow::Result<Vec<(NodeIndex, usize)>> {
+    let start_ply = |root: NodeIndex| ply_offset(&openings[root].start_position());
+    let (start, moves) = parse_line_key(line)?;
+    if moves.is_empty() {
+        let mut roots = openings
+            .roots()
+            .iter()
+            .copied()
+            .filter(|x| line.trim().is_empty() || openings[*x].start() == start.as_ref())
+            .collect::<Vec<_>>();
+        roots.sort();
+        return Ok(roots.into_iter().map(|x| (x, start_ply(x))).collect());
+    }
+    let node = find_line(openings, start.as_ref(), &moves)
+        .ok_or_else(|| anyhow::anyhow!("{} isn't in the graph", line))?;
+    let root = path_to(openings, node)[0];
+    Ok(vec![(node, start_ply(root) + moves.len() - 1)])
+}
+
+fn children(openings: &OpeningGraph, node: NodeIndex) -> Vec<NodeIndex> {
+    let mut children = openings
+        .neighbors_directed(node, Direction::Outgoing)
+        .collect::<Vec<_>>();
+    children.sort();
+    children
+}
+
+/// The graph, or the subtree starting at the end of `line`, as a GraphViz digraph
+pub fn to_dot(openings: &OpeningGraph, name: &str, line: &str) -> anyhow::Result<String> {
+    let roots = export_roots(openings, line)?;
+    let mut res = String::new();
+    writeln!(res, "digraph {} {{", name)?;
+    writeln!(res, "    node [shape=box, style=filled];")?;
+    let mut stack = roots.into_iter().rev().collect::<Vec<_>>();
+    while let Some((node, ply)) = stack.pop() {
+        let move_node = &openings[node];
+        let number = if ply.is_multiple_of(2) {
+            format!("{}.", ply / 2 + 1)
+        } else {
+            format!("{}...", ply / 2 + 1)
+        };
+        let label = match move_node.games {
+            0 => format!("{} {}", number, move_node.san),
+            games => format!("{} {} ({})", number, move_node.san, games),
+        };
+        let colour = if in_prep(move_node) {
+            PREP_COLOUR
+        } else {
+            GAME_COLOUR
+        };
+        let style = match move_node.priority {
+            Priority::Primary => "filled",
+            Priority::Alternative => "filled,dashed",
+        };
+        writeln!(
+            res,
+            "    {} [label=\"{}\", fillcolor=\"{}\", style=\"{}\"];",
+            node.index(),
+            label,
+            colour,
+            style
+        )?;
+        let children = children(openings, node);
+        for child in &children {
+            writeln!(res, "    {} -> {};", node.index(), child.index())?;
+        }
+        stack.extend(children.into_iter().rev().map(|x| (x, ply + 1)));
+    }
+    writeln!(res, "}}")?;
+    Ok(res)
+}
+
+/// The graph, or the subtree starting at the end of `line`, as nested nodes
+pub fn to_tree(openings: &OpeningGraph, line: &str) -> anyhow::Result<Vec<TreeNode>> {
+    fn build(openings: &OpeningGraph, node: NodeIndex) -> TreeNode {
+        let move_node = &openings[node];
+        TreeNode {
+            san: move_node.san.to_string(),
+            frequency: move_node.games,
+            in_prep: in_prep(move_node),
+            priority: move_node.priority,
+            comments: move_node.comments().to_vec(),
+            children: children(openings, node)
+                .into_iter()
+                .map(|x| build(openings, x))
+                .collect(),
+        }
+    }
+    let roots = export_roots(openings, line)?;
+    Ok(roots.into_iter().map(|(x, _)| build(openings, x)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OpeningDatabase;
+    use shakmaty::Color;
+    use std::path::Path;
+
+    #[test]
+    fn export_subtree() {
+        let repertoire = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let game = "[White \"me\"]\n[Black \"them\"]\n\n1. e4 e5 2. Nc3 Nf6 3. f4 d5 4. fxe5 Nxe4 \
+                    5. Nf3 Bc5 6. d4 Bb4 7. h3 *\n\n";
+        let games =
+            OpeningDatabase::load_multigame_pgn(game.repeat(2).as_bytes(), "me".into()).unwrap();
+        let db = export_database(&repertoire, Some(&games));
+        let graph = db.graph(Color::White);
+        let moves = "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5";
+
+        let tree = to_tree(graph, moves).unwrap();
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].san, "Bc5");
+        let replies = tree[0]
+            .children
+            .iter()
+            .map(|x| x.san.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(replies, vec!["Qe2", "d4"]);
+        // Moves count the games they were played in, the games leave the prep with 7. h3
+        assert_eq!((tree[0].frequency, tree[0].in_prep), (2, true));
+        assert_eq!(tree[0].children[0].frequency, 0);
+        let bb4 = &tree[0].children[1].children[0];
+        assert_eq!(
+            (bb4.san.as_str(), bb4.frequency, bb4.in_prep),
+            ("Bb4", 2, true)
+        );
+        let h3 = bb4.children.iter().find(|x| x.san == "h3").unwrap();
+        assert_eq!((h3.frequency, h3.in_prep), (2, false));
+
+        let dot = to_dot(graph, "white", moves).unwrap();
+        assert!(dot.starts_with("digraph white {"));
+        assert!(dot.contains("label=\"5... Bc5 (2)\", fillcolor=\"lightblue\""));
+        assert!(dot.contains("label=\"6. Qe2\", fillcolor=\"lightblue\""));
+        assert!(dot.contains("label=\"7. h3 (2)\", fillcolor=\"lightgrey\""));
+        assert!(!dot.contains("1. e4"));
+
+        assert!(to_tree(graph, "d4").is_err());
+        let everything = to_tree(graph, "").unwrap();
+        assert_eq!(everything[0].san, "e4");
+    }
+}
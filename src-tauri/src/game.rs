@@ -1 +1,87 @@
+//! Things to help the player out while they're in the middle of a drill.
+use crate::db::OpeningNode;
+use pgn_reader::Nag;
+use serde::Serialize;
+use shakmaty::{CastlingSide, Chess, Move, Position};
 
+/// How much of the prep move we give away, each level reveals everything the previous ones did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum HintLevel {
+    /// Highlight the piece that should move
+    Piece,
+    /// Show where it should go
+    Destination,
+    /// Give the move in SAN
+    San,
+    /// Show whatever the prep had to say about the move
+    Comment,
+}
+
+impl HintLevel {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Piece => Self::Destination,
+            Self::Destination => Self::San,
+            Self::San | Self::Comment => Self::Comment,
+        }
+    }
+
+    /// How much of a move's review score is lost by needing this hint to find it
+    pub fn penalty(self) -> f32 {
+        match self {
+            Self::Piece => 0.25,
+            Self::Destination => 0.5,
+            Self::San | Self::Comment => 0.75,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Hint {
+    pub level: Option<HintLevel>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub san: Option<String>,
+    pub comments: Vec<String>,
+}
+
+impl Hint {
+    pub fn new(level: HintLevel, node: &OpeningNode, game: &Chess) -> anyhow::Result<Self> {
+        let mv = node.san.san.to_move(game)?;
+        let to = match mv {
+            Move::Castle { king, rook } => {
+                CastlingSide::from_king_side(king < rook).king_to(game.turn())
+            }
+            _ => mv.to(),
+        };
+        let mut hint = Self {
+            level: Some(level),
+            from: mv.from().map(|x| x.to_string()),
+            ..Default::default()
+        };
+        if level >= HintLevel::Destination {
+            hint.to = Some(to.to_string());
+        }
+        if level >= HintLevel::San {
+            let glyphs = node.nags().iter().filter_map(glyph).collect::<String>();
+            hint.san = Some(format!("{}{}", node.san, glyphs));
+        }
+        if level >= HintLevel::Comment {
+            hint.comments = node.comments().to_vec();
+        }
+        Ok(hint)
+    }
+}
+
+/// How move annotations are usually written, other NAGs are about the position so aren't shown
+fn glyph(nag: &Nag) -> Option<&'static str> {
+    match nag.0 {
+        1 => Some("!"),
+        2 => Some("?"),
+        3 => Some("!!"),
+        4 => Some("??"),
+        5 => Some("!?"),
+        6 => Some("?!"),
+        _ => None,
+    }
+}
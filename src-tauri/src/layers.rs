@@ -0,0 +1,298 @@
+//! The repertoire and the different sets of games kept side by side as layers, with one index of
+//! the positions in all of them so a position can be looked up in every layer at once.
+use crate::db::OpeningDatabase;
+use crate::masters::MastersBook;
+use crate::polyglot::hash;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use pgn_reader::SanPlus;
+use serde::{Deserialize, Serialize};
+use shakmaty::{Chess, Color, Position};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    Repertoire,
+    /// The user's own games
+    MyGames,
+    /// Games of the opponent being prepared for
+    OpponentGames,
+    /// Games between strong players
+    Masters,
+}
+
+impl Layer {
+    /// Which of the layer's graphs to look in for the `color` repertoire. Opponents play the
+    /// other colour against it.
+    fn graph_color(self, color: Color) -> Color {
+        match self {
+            Self::OpponentGames => !color,
+            _ => color,
+        }
+    }
+}
+
+/// A move from a position and where it's found
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MoveStats {
+    pub san: String,
+    /// Whether the move is in the repertoire
+    pub in_prep: bool,
+    /// How many games the move was played in for each layer of games
+    pub games: BTreeMap<Layer, u64>,
+}
+
+impl MoveStats {
+    pub fn total_games(&self) -> u64 {
+        self.games.values().sum()
+    }
+}
+
+/// A move in one of the layers' graphs
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    layer: Layer,
+    color: Color,
+    node: NodeIndex,
+}
+
+#[derive(Clone)]
+pub struct LayeredDatabase {
+    layers: BTreeMap<Layer, OpeningDatabase>,
+    /// Moves made from each position, keyed by the position's zobrist hash
+    index: HashMap<u64, Vec<IndexEntry>>,
+    /// The positions each layer has entries for, so a layer can be taken out of the index without
+    /// going through every position
+    keys: BTreeMap<Layer, Vec<u64>>,
+    /// Master games are kept as counts rather than a graph as there's so many of them
+    masters: Option<MastersBook>,
+}
+
+impl Default for LayeredDatabase {
+    fn default() -> Self {
+        Self::new(OpeningDatabase::default())
+    }
+}
+
+impl LayeredDatabase {
+    /// There's always a repertoire, the other layers are added as they're loaded
+    pub fn new(repertoire: OpeningDatabase) -> Self {
+        let mut res = Self {
+            layers: BTreeMap::new(),
+            index: HashMap::new(),
+            keys: BTreeMap::new(),
+            masters: None,
+        };
+        res.set_layer(Layer::Repertoire, repertoire);
+        res
+    }
+
+    pub fn repertoire(&self) -> &OpeningDatabase {
+        &self.layers[&Layer::Repertoire]
+    }
+
+    pub fn layer(&self, layer: Layer) -> Option<&OpeningDatabase> {
+        self.layers.get(&layer)
+    }
+
+    pub fn masters(&self) -> Option<&MastersBook> {
+        self.masters.as_ref()
+    }
+
+    pub fn set_masters(&mut self, masters: Option<MastersBook>) {
+        self.masters = masters;
+    }
+
+    /// Add or replace a layer
+    pub fn set_layer(&mut self, layer: Layer, db: OpeningDatabase) {
+        self.remove_from_index(layer);
+        self.layers.insert(layer, db);
+        self.add_to_index(layer);
+    }
+
+    /// Remove a layer, removing the repertoire leaves an empty one
+    pub fn remove_layer(&mut self, layer: Layer) -> Option<OpeningDatabase> {
+        self.remove_from_index(layer);
+        let res = self.layers.remove(&layer);
+        if layer == Layer::Repertoire {
+            self.set_layer(layer, OpeningDatabase::default());
+        }
+        res
+    }
+
+    /// Change a layer in place, it's reindexed afterwards
+    pub fn update_layer(&mut self, layer: Layer, update: impl FnOnce(&mut OpeningDatabase)) {
+        self.remove_from_index(layer);
+        update(self.layers.entry(layer).or_default());
+        self.add_to_index(layer);
+    }
+
+    fn remove_from_index(&mut self, layer: Layer) {
+        for key in self.keys.remove(&layer).unwrap_or_default() {
+            if let Some(entries) = self.index.get_mut(&key) {
+                entries.retain(|x| x.layer != layer);
+                if entries.is_empty() {
+                    self.index.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn add_to_index(&mut self, layer: Layer) {
+        let db = &self.layers[&layer];
+        let mut keys = vec![];
+        for color in Color::ALL {
+            let graph = db.graph(color);
+            let mut stack = graph
+                .roots()
+                .iter()
+                .copied()
+                .map(|x| (x, graph[x].start_position()))
+                .collect::<Vec<_>>();
+            while let Some((node, position)) = stack.pop() {
+                let key = hash(&position);
+                let entries = self.index.entry(key).or_default();
+                if entries.iter().all(|x| x.layer != layer) {
+                    keys.push(key);
+                }
+                entries.push(IndexEntry { layer, color, node });
+                // Illegal moves were already reported when the layer was loaded
+                let Ok(mv) = graph[node].san.san.to_move(&position) else {
+                    continue;
+                };
+                let mut next = position;
+                next.play_unchecked(&mv);
+                for child in graph.neighbors_directed(node, Direction::Outgoing) {
+                    stack.push((child, next.clone()));
+                }
+            }
+        }
+        self.keys.insert(layer, keys);
+    }
+
+    /// Every move from `position` in any layer, for the `color` repertoire. Moves in the prep come
+    /// first, then the most played.
+    pub fn lookup(&self, color: Color, position: &Chess) -> Vec<MoveStats> {
+        let mut res: Vec<MoveStats> = vec![];
+        let entries = self.index.get(&hash(position));
+        for entry in entries.into_iter().flatten() {
+            if entry.color != entry.layer.graph_color(color) {
+                continue;
+            }
+            let node = &self.layers[&entry.layer].graph(entry.color)[entry.node];
+            // Written out again so moves from different files compare the same
+            let Ok(mv) = node.san.san.to_move(position) else {
+                continue;
+            };
+            let san = SanPlus::from_move(position.clone(), &mv).to_string();
+            let i = match res.iter().position(|x| x.san == san) {
+                Some(i) => i,
+                None => {
+                    res.push(MoveStats {
+                        san,
+                        ..Default::default()
+                    });
+                    res.len() - 1
+                }
+            };
+            match entry.layer {
+                Layer::Repertoire => res[i].in_prep = true,
+                layer => *res[i].games.entry(layer).or_default() += u64::from(node.games),
+            }
+        }
+        for master in self.masters.iter().flat_map(|x| x.stats(position)) {
+            match res.iter_mut().find(|x| x.san == master.san) {
+                Some(stats) => {
+                    stats.games.insert(Layer::Masters, master.games);
+                }
+                None => res.push(MoveStats {
+                    san: master.san,
+                    in_prep: false,
+                    games: BTreeMap::from([(Layer::Masters, master.games)]),
+                }),
+            }
+        }
+        res.sort_by(|a, b| {
+            b.in_prep
+                .cmp(&a.in_prep)
+                .then(b.total_games().cmp(&a.total_games()))
+                .then(a.san.cmp(&b.san))
+        });
+        res
+    }
+
+    /// Look up the position after playing `moves` from `start`
+    pub fn lookup_line(
+        &self,
+        color: Color,
+        start: &Chess,
+        moves: &[SanPlus],
+    ) -> anyhow::Result<Vec<MoveStats>> {
+        let mut position = start.clone();
+        for san in moves {
+            let mv = san.san.to_move(&position)?;
+            position.play_unchecked(&mv);
+        }
+        Ok(self.lookup(color, &position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn sans(moves: &str) -> Vec<SanPlus> {
+        moves
+            .split_whitespace()
+            .map(|x| SanPlus::from_ascii(x.as_bytes()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lookup_layers() {
+        let mut db = LayeredDatabase::new(OpeningDatabase::load(Path::new("prep")).unwrap());
+        let games = fs::File::open("tests/resources/games.pgn").unwrap();
+        let games = OpeningDatabase::load_multigame_pgn(games, "xd009642".to_string()).unwrap();
+        db.set_layer(Layer::MyGames, games);
+        let start = Chess::default();
+
+        // In prep and played in one of my games
+        let replies = db.lookup_line(Color::Black, &start, &sans("e4")).unwrap();
+        let c6 = replies.iter().find(|x| x.san == "c6").unwrap();
+        assert!(c6.in_prep);
+        assert_eq!(c6.games, BTreeMap::from([(Layer::MyGames, 1)]));
+
+        // Transpositions end up at the same position
+        let qgd = db
+            .lookup_line(Color::Black, &start, &sans("d4 d5 c4 e6"))
+            .unwrap();
+        let transposed = db
+            .lookup_line(Color::Black, &start, &sans("c4 e6 d4 d5"))
+            .unwrap();
+        assert!(!qgd.is_empty());
+        assert_eq!(qgd, transposed);
+
+        // The white repertoire doesn't start 1. d4 but I've played it
+        let first = db.lookup(Color::White, &start);
+        assert_eq!(first[0].san, "e4");
+        assert!(first[0].in_prep);
+        let d4 = first.iter().find(|x| x.san == "d4").unwrap();
+        assert!(!d4.in_prep);
+        assert_eq!(d4.total_games(), 1);
+
+        db.remove_layer(Layer::MyGames);
+        let first = db.lookup(Color::White, &start);
+        assert!(first.iter().all(|x| x.san != "d4"));
+        // Only the removed layer's entries are gone
+        assert!(first[0].in_prep && first[0].games.is_empty());
+        assert!(db
+            .index
+            .values()
+            .flatten()
+            .all(|x| x.layer != Layer::MyGames));
+        assert!(!db.keys.contains_key(&Layer::MyGames));
+    }
+}
@@ -1,53 +1,458 @@
+use anyhow::Context;
+use serde::Serialize;
+use shakmaty::fen::Fen;
 use shakmaty::{san::SanPlus, Chess, Color, Position, Role, Square};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Mutex;
+use std::time::SystemTime;
+use tauri::Manager;
 use tracing::{error, info};
 
+pub mod audit;
+pub mod cli;
 pub mod clients;
 pub mod config;
 pub mod db;
+pub mod drill;
+pub mod eco;
+pub mod edit;
+pub mod engine;
+pub mod export;
 pub mod game;
+pub mod layers;
+pub mod lint;
+pub mod masters;
+pub mod parallel;
+pub mod pgn_edit;
+pub mod polyglot;
+pub mod scout;
+pub mod session;
+pub mod stats;
+pub mod store;
+pub mod tree;
+pub mod watcher;
 
+pub use crate::audit::*;
 pub use crate::clients::chess_com::*;
 pub use crate::config::*;
 pub use crate::db::*;
+pub use crate::drill::*;
+pub use crate::eco::*;
+pub use crate::edit::*;
+pub use crate::engine::*;
+pub use crate::export::*;
+pub use crate::game::*;
+pub use crate::layers::*;
+pub use crate::lint::*;
+pub use crate::masters::*;
+pub use crate::scout::*;
+pub use crate::session::*;
+pub use crate::stats::*;
+pub use crate::tree::*;
+pub use crate::watcher::*;
 
 pub struct ChessState(Mutex<App>);
 
+pub struct WatcherState(Mutex<RepertoireWatcher>);
+
+/// What the UI needs to know when a new round starts
+#[derive(Clone, Debug, Serialize)]
+pub struct Round {
+    pub board: String,
+    /// The side the player is moving for
+    pub color: String,
+}
+
+/// A move being shown to the player in learn mode
+#[derive(Clone, Debug, Serialize)]
+pub struct LearnStep {
+    pub board: String,
+    pub san: Option<String>,
+    pub comments: Vec<String>,
+}
+
 #[derive(Clone)]
 pub struct App {
-    chess_com_usernames: Vec<String>,
-    db: OpeningDatabase,
+    config: Config,
+    /// The repertoire, the user's downloaded games and any opponent's games
+    db: LayeredDatabase,
+    filter: DrillFilter,
+    drill_config: DrillConfig,
+    /// The repertoire being drilled
     color: Color,
+    /// The side the player moves for, only differs from `color` if we're looking at the
+    /// opponent's replies
+    side: Color,
     game: Chess,
     game_state: Option<GameState>,
     moves: Vec<SanPlus>,
+    /// Every move played on the board since it was last reset
+    session: Session,
+    learn: Option<LearnSession>,
+    progress: LearnProgress,
+    history: DrillHistory,
+    edits: RepertoireEdits,
+    /// Prep that's been reloaded while it was being drilled, it's swapped in once the drill ends
+    pending_prep: Vec<(Color, Prep)>,
+    /// Repertoire files the app wrote and when, so the watcher doesn't reload them again
+    written: HashMap<PathBuf, SystemTime>,
 }
 
-fn create_app() -> anyhow::Result<App> {
-    let config = Config::load()?;
-    let chess_dot_com = ChessComClient::new();
-    let db = OpeningDatabase::load_default()?;
+impl App {
+    /// Swap in freshly loaded prep unless it's being drilled right now
+    fn update_prep(&mut self, color: Color, prep: Prep) {
+        let drilling = self.color == color
+            && self
+                .game_state
+                .as_ref()
+                .map(|x| x.still_running())
+                .unwrap_or(false);
+        self.pending_prep.retain(|(c, _)| *c != color);
+        self.pending_prep.push((color, prep));
+        if drilling {
+            info!("Waiting for the drill to end before updating the prep");
+        } else {
+            self.apply_pending_prep();
+        }
+    }
+
+    fn apply_pending_prep(&mut self) {
+        for (color, prep) in self.pending_prep.drain(..) {
+            self.db
+                .update_layer(Layer::Repertoire, |db| db.set_prep(color, prep));
+            if self.color == color {
+                // Node indexes from the old prep don't mean anything anymore
+                self.game_state = None;
+                if let Some(learn) = self.learn.as_mut() {
+                    *learn =
+                        LearnSession::new(color, self.db.repertoire().graph(color), &self.progress);
+                }
+            }
+        }
+    }
+
+    /// Reload the repertoire with new folders, the config is only saved and used once they've
+    /// loaded. This ends any drill in progress.
+    fn reload_repertoire(&mut self, config: Config) -> anyhow::Result<()> {
+        let db = OpeningDatabase::load_repertoire(&config.active().repertoire, &self.edits)?;
+        config.save()?;
+        self.config = config;
+        self.db.set_layer(Layer::Repertoire, db);
+        self.game_state = None;
+        self.learn = None;
+        self.pending_prep.clear();
+        Ok(())
+    }
 
-    let game_state = db.start_drill(Color::White, &[]);
+    /// Switch to another profile, loading its repertoire and drill data. This ends any drill in
+    /// progress.
+    fn switch_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut config = self.config.clone();
+        config.use_profile(name)?;
+        self.apply_config(config)
+    }
+
+    /// Check and save new settings then start using them. The repertoire is only reloaded if its
+    /// folders or the profile changed, in which case any drill in progress ends.
+    fn apply_config(&mut self, config: Config) -> anyhow::Result<()> {
+        config.validate()?;
+        let profile_changed = config.profile != self.config.profile;
+        let repertoire_changed =
+            profile_changed || config.active().repertoire != self.config.active().repertoire;
+        let edits = if profile_changed {
+            RepertoireEdits::load(&config)
+        } else {
+            self.edits.clone()
+        };
+        let db = if repertoire_changed {
+            Some(OpeningDatabase::load_repertoire(
+                &config.active().repertoire,
+                &edits,
+            )?)
+        } else {
+            None
+        };
+        config.save()?;
+        if profile_changed || config.active().chess_com != self.config.active().chess_com {
+            self.db
+                .set_layer(Layer::MyGames, load_cached_games(&config));
+        }
+        self.drill_config = config.active().drill.clone();
+        if profile_changed {
+            info!("Switched to profile {}", config.profile);
+            self.progress = LearnProgress::load(&config);
+            self.history = DrillHistory::load(&config);
+            self.db.remove_layer(Layer::OpponentGames);
+            self.db.set_masters(load_masters(&config));
+        }
+        self.config = config;
+        self.edits = edits;
+        if let Some(db) = db {
+            self.db.set_layer(Layer::Repertoire, db);
+            self.learn = None;
+            self.pending_prep.clear();
+            self.reset_board();
+        }
+        Ok(())
+    }
+
+    /// Plays a move from the prep on the board
+    fn play_san(&mut self, san: &SanPlus, assessment: Option<MoveAssessment>) {
+        match san.san.to_move(&self.game) {
+            Ok(mv) => {
+                self.game.play_unchecked(&mv);
+                self.session.push(san.clone(), assessment);
+            }
+            Err(e) => error!("Couldn't play {}: {}", san, e),
+        }
+    }
+
+    /// Reset the board to where the drill filter starts from
+    fn reset_board(&mut self) {
+        let start = match self.filter.start_fen() {
+            Ok(start) => start,
+            Err(e) => {
+                error!("Invalid drill filter start: {}", e);
+                None
+            }
+        };
+        self.reset_board_to(start);
+    }
+
+    fn reset_board_to(&mut self, start: Option<Fen>) {
+        self.game = start
+            .as_ref()
+            .and_then(|x| setup_position(x).ok())
+            .unwrap_or_default();
+        self.session = Session::new(start);
+        self.game_state = None;
+        self.moves.clear();
+    }
+
+    /// The opening reached by the moves on the board
+    fn opening(&self) -> Option<Opening> {
+        Classifier::bundled()
+            .classify(&self.session.start_position(), &self.session.sans())
+            .cloned()
+    }
+
+    /// Save the moves played on the board as a PGN to `file`, or a new file in the `sessions`
+    /// folder of the data directory
+    fn export_session(&self, file: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+        if self.session.is_empty() {
+            anyhow::bail!("No moves have been played");
+        }
+        let file = match file {
+            Some(file) => file,
+            None => {
+                let dir = self.config.data_dir().join("sessions");
+                fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+                let name = self.session.started.format("%Y-%m-%d_%H-%M-%S");
+                dir.join(format!("{}.pgn", name))
+            }
+        };
+        let source = SessionSource {
+            side: self.side,
+            repertoire: self.config.active().repertoire.folders(self.color),
+            engine: self.config.active().engine.as_ref().map(|x| &x.path),
+        };
+        info!("Saving session to {}", file.display());
+        fs::write(&file, self.session.to_pgn(&source))
+            .with_context(|| format!("writing {}", file.display()))?;
+        Ok(file)
+    }
+
+    /// Add the moves played on the board to the repertoire being drilled. They're written to
+    /// `file` if given, otherwise to `additions.pgn` in the first folder of the repertoire.
+    fn add_to_repertoire(&mut self, file: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+        if self.session.is_empty() {
+            anyhow::bail!("No moves have been played");
+        }
+        let folders = self.config.active().repertoire.folders(self.color).to_vec();
+        let file = match file.map(|x| canonical_file(&x)).transpose()? {
+            Some(file)
+                if folders
+                    .iter()
+                    .filter_map(|x| x.canonicalize().ok())
+                    .any(|x| file.starts_with(x)) =>
+            {
+                file
+            }
+            Some(file) => {
+                anyhow::bail!("{} isn't in the {} repertoire", file.display(), self.color)
+            }
+            None => match folders.first() {
+                Some(folder) => canonical_file(&folder.join("additions.pgn"))?,
+                None => anyhow::bail!("There are no folders in the {} repertoire", self.color),
+            },
+        };
+        let text = if file.exists() {
+            fs::read_to_string(&file).with_context(|| format!("reading {}", file.display()))?
+        } else {
+            String::new()
+        };
+        let new_text = pgn_edit::add_line(
+            &text,
+            self.session.start.as_ref(),
+            &self.session.sans(),
+            "Additions",
+        );
+        if new_text == text {
+            info!("Line is already in {}", file.display());
+            return Ok(file);
+        }
+        let line = self
+            .session
+            .moves
+            .iter()
+            .map(|x| &x.san)
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        info!("Adding {} to {}", line.join(" "), file.display());
+        fs::write(&file, new_text).with_context(|| format!("writing {}", file.display()))?;
+        if let Ok(modified) = fs::metadata(&file).and_then(|x| x.modified()) {
+            self.written.insert(file.clone(), modified);
+        }
+        let mut prep = Prep::load(self.color, &folders)?;
+        apply_edits(&mut prep, self.color, self.edits.edits(self.color));
+        self.update_prep(self.color, prep);
+        Ok(file)
+    }
+
+    /// Whether a file is still as the app last wrote it, the prep was already updated then
+    fn wrote(&self, file: &Path) -> bool {
+        let modified = fs::metadata(file).and_then(|x| x.modified()).ok();
+        modified.is_some() && self.written.get(file) == modified.as_ref()
+    }
+
+    /// Change one of the repertoires and save the edit so it's applied whenever it's loaded
+    fn edit_repertoire(&mut self, color: Color, edit: Edit) -> anyhow::Result<()> {
+        info!("Editing {} repertoire: {:?}", color, edit);
+        let mut prep = self.db.repertoire().prep(color);
+        // Edits made while a drill is running haven't been applied yet
+        if let Some((_, pending)) = self.pending_prep.iter().find(|(c, _)| *c == color) {
+            prep = pending.clone();
+        }
+        edit.apply(color, &mut prep)?;
+        self.edits.push(color, edit);
+        self.edits.save(&self.config)?;
+        self.update_prep(color, prep);
+        Ok(())
+    }
+}
+
+/// The canonical path of a file that might not exist yet, so paths with `..` or links can't get
+/// outside the folder they look like they're in
+fn canonical_file(file: &Path) -> anyhow::Result<PathBuf> {
+    let name = file
+        .file_name()
+        .with_context(|| format!("{} isn't a file", file.display()))?;
+    let parent = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = parent
+        .canonicalize()
+        .with_context(|| format!("finding {}", parent.display()))?;
+    Ok(parent.join(name))
+}
+
+fn create_app(config: Option<PathBuf>, profile: Option<&str>) -> anyhow::Result<App> {
+    let mut config = Config::load_from(config.as_deref())?;
+    if let Some(profile) = profile {
+        config.use_profile(profile)?;
+    }
+    if let Err(e) = config.validate() {
+        error!("{:#}", e);
+    }
+    let edits = RepertoireEdits::load(&config);
+    let mut db = LayeredDatabase::new(OpeningDatabase::load_repertoire(
+        &config.active().repertoire,
+        &edits,
+    )?);
+    db.set_layer(Layer::MyGames, load_cached_games(&config));
+    db.set_masters(load_masters(&config));
+
+    let game_state = db.repertoire().start_drill(Color::White, &[]);
+    let progress = LearnProgress::load(&config);
+    let history = DrillHistory::load(&config);
 
     Ok(App {
-        chess_com_usernames: config.chess_com,
+        drill_config: config.active().drill.clone(),
+        config,
         db,
+        filter: DrillFilter::default(),
         color: Color::White,
+        side: Color::White,
         game: Chess::new(),
         moves: vec![],
+        session: Session::default(),
         game_state,
+        learn: None,
+        progress,
+        history,
+        edits,
+        pending_prep: vec![],
+        written: HashMap::new(),
     })
 }
 
-pub fn launch() {
+/// Start the app, reading the config from `config` if it's given. `profile` picks the profile to
+/// use instead of the last one used
+pub fn launch(config: Option<PathBuf>, profile: Option<&str>) {
     tauri::Builder::default()
-        .manage(ChessState(Mutex::new(create_app().unwrap())))
+        .manage(ChessState(Mutex::new(create_app(config, profile).unwrap())))
+        .setup(|app| {
+            let mut watcher = RepertoireWatcher::new(app.handle())?;
+            watcher.watch(
+                &app.state::<ChessState>()
+                    .0
+                    .lock()
+                    .unwrap()
+                    .config
+                    .active()
+                    .repertoire,
+            );
+            app.manage(WatcherState(Mutex::new(watcher)));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             commands::move_piece,
             commands::start,
-            commands::reset
+            commands::reset,
+            commands::hint,
+            commands::chapters,
+            commands::set_drill_filter,
+            commands::set_drill_config,
+            commands::get_config,
+            commands::update_config,
+            commands::profiles,
+            commands::add_profile,
+            commands::switch_profile,
+            commands::learn_start,
+            commands::learn_show,
+            commands::learn_quiz,
+            commands::learn_status,
+            commands::opening,
+            commands::scout_opponent,
+            commands::clear_opponent,
+            commands::export_session,
+            commands::explore,
+            commands::stats,
+            commands::repertoire_folders,
+            commands::add_repertoire_folder,
+            commands::remove_repertoire_folder,
+            commands::add_to_repertoire,
+            commands::prune_line,
+            commands::set_priority,
+            commands::promote_line,
+            commands::annotate_move,
+            commands::rename_chapter,
+            commands::repertoire_tree,
+            commands::repertoire_dot
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -58,42 +463,195 @@ pub mod commands {
     use tauri::State;
 
     #[tauri::command]
-    pub fn start(state: State<ChessState>) -> String {
+    pub fn start(state: State<ChessState>) -> Round {
         let mut state = state.0.lock().unwrap();
-        if state.color == Color::White {
-            state.game_state = state.db.start_drill(Color::White, &state.moves);
-        } else {
-            state.game_state = state.db.start_drill(Color::Black, &state.moves);
+        state.apply_pending_prep();
+        // A drill ends learning, otherwise finishing it would count as learning a line
+        state.learn = None;
+        // The colour only moves on once a round has been played, and the moves from the last
+        // round don't belong in the other repertoire
+        let finished = state
+            .game_state
+            .as_ref()
+            .is_some_and(|x| !x.still_running());
+        if finished {
+            let color = state.drill_config.next_repertoire(state.color);
+            if color != state.color {
+                state.color = color;
+                state.reset_board();
+            }
         }
+        state.side = state.drill_config.side(state.color);
+        info!(
+            "Drilling {} repertoire as {}",
+            state.color.char(),
+            state.side.char()
+        );
+        state.game_state = state.db.repertoire().start_drill_for_side(
+            state.color,
+            state.side,
+            &state.moves,
+            &state.filter,
+        );
         let mut game_state = state.game_state.take();
         if let Some(game_state) = game_state.as_mut() {
+            if let Some(games) = state.db.layer(Layer::OpponentGames) {
+                game_state.set_move_weights(move_weights(
+                    state.color,
+                    state.db.repertoire().graph(state.color),
+                    games,
+                ));
+            } else if let Some(masters) = state.db.masters().filter(|_| state.drill_config.masters)
+            {
+                game_state.set_move_weights(masters_weights(
+                    state.db.repertoire().graph(state.color),
+                    masters,
+                ));
+            }
             if !game_state.is_player_turn() {
-                let mv = game_state.make_move(state.db.graph(state.color));
+                let mv = game_state.make_move(state.db.repertoire().graph(state.color));
                 if let Some(mv) = mv {
-                    let game = state.game.clone();
-
-                    let mv = mv.san.to_move(&game).unwrap();
-                    let new_game = game.clone().play(&mv).unwrap();
-                    state.game = new_game;
+                    state.play_san(&mv, Some(MoveAssessment::InPrep));
                 }
             }
         }
         state.game_state = game_state;
-        state.game.board().to_string()
+        Round {
+            board: state.game.board().to_string(),
+            color: state.side.to_string(),
+        }
+    }
+
+    #[tauri::command]
+    pub fn set_drill_config(config: DrillConfig, state: State<ChessState>) {
+        info!("Setting drill config: {:?}", config);
+        let mut state = state.0.lock().unwrap();
+        state.config.active_mut().drill = config.clone();
+        state.drill_config = config;
+        if let Err(e) = state.config.save() {
+            error!("Couldn't save drill config: {}", e);
+        }
+    }
+
+    #[tauri::command]
+    pub fn get_config(state: State<ChessState>) -> Config {
+        let state = state.0.lock().unwrap();
+        state.config.clone()
+    }
+
+    /// Replace the settings, they're checked and saved before the app starts using them. The
+    /// settings that were applied are returned.
+    #[tauri::command]
+    pub fn update_config(
+        config: Config,
+        state: State<ChessState>,
+        watcher: State<WatcherState>,
+    ) -> Result<Config, String> {
+        let mut state = state.0.lock().unwrap();
+        let mut new = state.config.clone();
+        new.update(config).map_err(|e| format!("{:#}", e))?;
+        info!("Updating config");
+        state.apply_config(new).map_err(|e| format!("{:#}", e))?;
+        watcher
+            .0
+            .lock()
+            .unwrap()
+            .watch(&state.config.active().repertoire);
+        Ok(state.config.clone())
+    }
+
+    /// The names of the profiles and the one in use
+    #[tauri::command]
+    pub fn profiles(state: State<ChessState>) -> (Vec<String>, String) {
+        let state = state.0.lock().unwrap();
+        let names = state.config.profiles.keys().cloned().collect();
+        (names, state.config.profile.clone())
+    }
+
+    #[tauri::command]
+    pub fn add_profile(name: String, state: State<ChessState>) -> Result<(), String> {
+        let mut state = state.0.lock().unwrap();
+        info!("Adding profile {}", name);
+        state
+            .config
+            .add_profile(&name)
+            .and_then(|_| state.config.save())
+            .map_err(|e| format!("{:#}", e))
+    }
+
+    /// Use another profile's repertoire, accounts and drill data
+    #[tauri::command]
+    pub fn switch_profile(
+        name: String,
+        state: State<ChessState>,
+        watcher: State<WatcherState>,
+    ) -> Result<(), String> {
+        let mut state = state.0.lock().unwrap();
+        state
+            .switch_profile(&name)
+            .map_err(|e| format!("{:#}", e))?;
+        watcher
+            .0
+            .lock()
+            .unwrap()
+            .watch(&state.config.active().repertoire);
+        Ok(())
     }
 
     #[tauri::command]
     pub fn reset(color: &str, state: State<ChessState>) {
         info!("Resetting board for {}", color);
         let mut state = state.0.lock().unwrap();
+        state.apply_pending_prep();
         let color = Color::from_str(color).unwrap();
-        state.color = color;
-        state.game = Chess::new();
-        state.game_state = None;
-        state.moves.clear();
+        // The UI gives us the side it's showing so work back to the repertoire from that
+        state.side = color;
+        state.color = state.drill_config.repertoire(color);
+        if state.learn.as_ref().map(|x| x.color) != Some(state.color) {
+            state.learn = None;
+        }
+        state.reset_board();
         info!("Board reset");
     }
 
+    #[tauri::command]
+    pub fn chapters(color: &str, state: State<ChessState>) -> Result<Vec<Chapter>, String> {
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        let state = state.0.lock().unwrap();
+        Ok(state.db.repertoire().chapters(color).to_vec())
+    }
+
+    /// Set the filter for the next drills, the board is reset as the filter can change the
+    /// starting position
+    #[tauri::command]
+    pub fn set_drill_filter(filter: DrillFilter, state: State<ChessState>) -> Result<(), String> {
+        info!("Setting drill filter: {:?}", filter);
+        filter.start_fen().map_err(|e| e.to_string())?;
+        let mut state = state.0.lock().unwrap();
+        state.filter = filter;
+        state.reset_board();
+        Ok(())
+    }
+
+    #[tauri::command]
+    pub fn hint(state: State<ChessState>) -> Hint {
+        let mut state = state.0.lock().unwrap();
+        let mut game_state = state.game_state.take();
+        let mut res = Hint::default();
+        if let Some(game_state) = game_state.as_mut() {
+            let graph = state.db.repertoire().graph(state.color);
+            if let Some((node, level)) = game_state.next_hint(graph) {
+                info!("Giving a {:?} hint", level);
+                match Hint::new(level, &graph[node], &state.game) {
+                    Ok(hint) => res = hint,
+                    Err(e) => error!("Prep move isn't legal: {}", e),
+                }
+            }
+        }
+        state.game_state = game_state;
+        res
+    }
+
     #[tauri::command]
     pub fn move_piece(from: &str, to: &str, promotion: &str, state: State<ChessState>) -> String {
         info!("Args: {}->{} {}", from, to, promotion);
@@ -128,30 +686,398 @@ pub mod commands {
         let game = state.game.clone();
         match game.play(game_move) {
             Ok(new_game) => {
-                state.game = new_game;
-                let mut game_state = state.game_state.take();
-                let graph = state.db.graph(state.color);
+                let app = &mut *state;
+                app.game = new_game;
+                let mut game_state = app.game_state.take();
+                let graph = app.db.repertoire().graph(app.color);
                 if let Some(game_state) = game_state.as_mut() {
+                    let position = game_state.current_move;
+                    let player_turn = game_state.is_player_turn();
+                    let hinted = game_state.current_hint().is_some();
+                    let running = game_state.still_running();
+                    let open = running && game_state.check_move(graph) != MoveAssessment::PrepEnded;
                     let prep_state = game_state.apply_move(&san, graph);
+                    app.session.push(san.clone(), running.then_some(prep_state));
                     info!("Prep status: {:?}", prep_state);
-                    if let Some(mv) = game_state.make_move(graph) {
-                        let game = state.game.clone();
+                    if prep_state == MoveAssessment::OutOfPrep {
+                        if let Some(opening) = app.opening() {
+                            info!("Left the prep with {} in the {}", san, opening);
+                        }
+                    }
+                    if running && player_turn && prep_state != MoveAssessment::PrepEnded {
+                        let correct = prep_state == MoveAssessment::InPrep;
+                        app.history.record(
+                            app.color,
+                            graph,
+                            game_state.start_fen(),
+                            position,
+                            correct,
+                            hinted,
+                        );
+                        if let Err(e) = app.history.save(&app.config) {
+                            error!("Couldn't save drill history: {}", e);
+                        }
+                    }
+                    if let Some(reply) = game_state.make_move(graph) {
+                        let game = app.game.clone();
 
-                        let mv = mv.san.to_move(&game).unwrap();
-                        let new_game = game.clone().play(&mv).unwrap();
-                        state.game = new_game;
+                        let mv = reply.san.to_move(&game).unwrap();
+                        let new_game = game.play(&mv).unwrap();
+                        app.game = new_game;
+                        app.session.push(reply, Some(MoveAssessment::InPrep));
+                    }
+                    // Score the round once, when it leaves the prep or runs out of it
+                    let ended = !game_state.still_running()
+                        || game_state.check_move(graph) == MoveAssessment::PrepEnded;
+                    if open && ended {
+                        app.history.record_round(
+                            app.color,
+                            graph,
+                            game_state.start_fen(),
+                            game_state.current_move,
+                            game_state.review_score(),
+                        );
+                        if let Err(e) = app.history.save(&app.config) {
+                            error!("Couldn't save drill history: {}", e);
+                        }
                     }
                 } else {
-                    state.moves.push(san);
+                    app.session.push(san.clone(), None);
+                    app.moves.push(san);
                 }
-                state.game_state = game_state;
+                app.game_state = game_state;
             }
             Err(e) => {
                 error!("{}", e);
             }
         }
+        let app = &mut *state;
+        if let (Some(learn), Some(game_state)) = (app.learn.as_mut(), app.game_state.as_ref()) {
+            let graph = app.db.repertoire().graph(app.color);
+            if learn.is_quizzing()
+                && !game_state.went_wrong()
+                && game_state.check_move(graph) == MoveAssessment::PrepEnded
+            {
+                learn.complete_line(graph, &mut app.progress);
+                if let Err(e) = app.progress.save(&app.config) {
+                    error!("Couldn't save learning progress: {}", e);
+                }
+            }
+        }
         state.game.board().to_string()
     }
+
+    /// Download an opponent's chess.com games to see how they'll play against a repertoire, drills
+    /// play their moves until [`clear_opponent`] is called
+    #[tauri::command]
+    pub async fn scout_opponent(
+        opponent: String,
+        color: String,
+        months: usize,
+        state: State<'_, ChessState>,
+    ) -> Result<ScoutReport, String> {
+        let color = Color::from_str(&color).map_err(|e| e.to_string())?;
+        // Don't hold the lock while downloading, and keep the download off the main thread
+        let config = state.0.lock().unwrap().config.clone();
+        let user = opponent.clone();
+        let games = tauri::async_runtime::spawn_blocking(move || {
+            ChessComClient::new()?.download_player_games(&config, &user, months)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{:#}", e))?;
+        let mut state = state.0.lock().unwrap();
+        let report = scout(&opponent, color, state.db.repertoire().graph(color), &games);
+        info!("Preparing against {}", opponent);
+        state.db.set_layer(Layer::OpponentGames, games);
+        Ok(report)
+    }
+
+    #[tauri::command]
+    pub fn clear_opponent(state: State<ChessState>) {
+        let mut state = state.0.lock().unwrap();
+        state.db.remove_layer(Layer::OpponentGames);
+    }
+
+    #[tauri::command]
+    pub fn opening(state: State<ChessState>) -> Option<Opening> {
+        let state = state.0.lock().unwrap();
+        state.opening()
+    }
+
+    /// Save the moves played since the board was reset as a PGN, returning where it was written
+    #[tauri::command]
+    pub fn export_session(
+        file: Option<PathBuf>,
+        state: State<ChessState>,
+    ) -> Result<PathBuf, String> {
+        let state = state.0.lock().unwrap();
+        state.export_session(file).map_err(|e| format!("{:#}", e))
+    }
+
+    /// The moves from the position after `moves` in the repertoire and every set of games loaded
+    #[tauri::command]
+    pub fn explore(
+        color: &str,
+        moves: Vec<String>,
+        state: State<ChessState>,
+    ) -> Result<Vec<MoveStats>, String> {
+        let state = state.0.lock().unwrap();
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        let moves = moves
+            .iter()
+            .map(|x| SanPlus::from_ascii(x.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        state
+            .db
+            .lookup_line(color, &Chess::default(), &moves)
+            .map_err(|e| e.to_string())
+    }
+
+    #[tauri::command]
+    pub fn stats(color: &str, state: State<ChessState>) -> Result<RepertoireStats, String> {
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        let state = state.0.lock().unwrap();
+        Ok(RepertoireStats::new(
+            color,
+            state.db.repertoire().graph(color),
+            &state.history,
+        ))
+    }
+
+    #[tauri::command]
+    pub fn repertoire_tree(
+        color: &str,
+        line: String,
+        state: State<ChessState>,
+    ) -> Result<Vec<TreeNode>, String> {
+        let state = state.0.lock().unwrap();
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        let db = export_database(state.db.repertoire(), state.db.layer(Layer::MyGames));
+        to_tree(db.graph(color), &line).map_err(|e| e.to_string())
+    }
+
+    #[tauri::command]
+    pub fn repertoire_dot(
+        color: &str,
+        line: String,
+        state: State<ChessState>,
+    ) -> Result<String, String> {
+        let state = state.0.lock().unwrap();
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        let db = export_database(state.db.repertoire(), state.db.layer(Layer::MyGames));
+        to_dot(db.graph(color), &color.to_string(), &line).map_err(|e| e.to_string())
+    }
+
+    #[tauri::command]
+    pub fn repertoire_folders(state: State<ChessState>) -> RepertoireFolders {
+        let state = state.0.lock().unwrap();
+        state.config.active().repertoire.clone()
+    }
+
+    #[tauri::command]
+    pub fn add_repertoire_folder(
+        color: &str,
+        folder: PathBuf,
+        state: State<ChessState>,
+        watcher: State<WatcherState>,
+    ) -> Result<(), String> {
+        let mut state = state.0.lock().unwrap();
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        if !folder.is_dir() {
+            return Err(format!("{} isn't a folder", folder.display()));
+        }
+        info!("Adding {} to the {} repertoire", folder.display(), color);
+        let mut config = state.config.clone();
+        let folders = config.active_mut().repertoire.folders_mut(color);
+        if !folders.contains(&folder) {
+            folders.push(folder);
+        }
+        state.reload_repertoire(config).map_err(|e| e.to_string())?;
+        watcher
+            .0
+            .lock()
+            .unwrap()
+            .watch(&state.config.active().repertoire);
+        Ok(())
+    }
+
+    #[tauri::command]
+    pub fn remove_repertoire_folder(
+        color: &str,
+        folder: PathBuf,
+        state: State<ChessState>,
+        watcher: State<WatcherState>,
+    ) -> Result<(), String> {
+        let mut state = state.0.lock().unwrap();
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        info!(
+            "Removing {} from the {} repertoire",
+            folder.display(),
+            color
+        );
+        let mut config = state.config.clone();
+        config
+            .active_mut()
+            .repertoire
+            .folders_mut(color)
+            .retain(|x| *x != folder);
+        state.reload_repertoire(config).map_err(|e| e.to_string())?;
+        watcher
+            .0
+            .lock()
+            .unwrap()
+            .watch(&state.config.active().repertoire);
+        Ok(())
+    }
+
+    #[tauri::command]
+    pub fn add_to_repertoire(
+        file: Option<PathBuf>,
+        state: State<ChessState>,
+    ) -> Result<PathBuf, String> {
+        let mut state = state.0.lock().unwrap();
+        state
+            .add_to_repertoire(file)
+            .map_err(|e| format!("{:#}", e))
+    }
+
+    #[tauri::command]
+    pub fn prune_line(color: &str, line: String, state: State<ChessState>) -> Result<(), String> {
+        edit_repertoire(color, Edit::Prune { line }, state)
+    }
+
+    #[tauri::command]
+    pub fn set_priority(
+        color: &str,
+        line: String,
+        priority: Priority,
+        state: State<ChessState>,
+    ) -> Result<(), String> {
+        edit_repertoire(color, Edit::SetPriority { line, priority }, state)
+    }
+
+    #[tauri::command]
+    pub fn promote_line(color: &str, line: String, state: State<ChessState>) -> Result<(), String> {
+        edit_repertoire(color, Edit::Promote { line }, state)
+    }
+
+    #[tauri::command]
+    pub fn annotate_move(
+        color: &str,
+        line: String,
+        comments: Vec<String>,
+        state: State<ChessState>,
+    ) -> Result<(), String> {
+        edit_repertoire(color, Edit::Annotate { line, comments }, state)
+    }
+
+    #[tauri::command]
+    pub fn rename_chapter(
+        color: &str,
+        file: PathBuf,
+        chapter: String,
+        name: String,
+        state: State<ChessState>,
+    ) -> Result<(), String> {
+        let edit = Edit::RenameChapter {
+            file,
+            chapter,
+            name,
+        };
+        edit_repertoire(color, edit, state)
+    }
+
+    fn edit_repertoire(color: &str, edit: Edit, state: State<ChessState>) -> Result<(), String> {
+        let mut state = state.0.lock().unwrap();
+        let color = Color::from_str(color).map_err(|e| e.to_string())?;
+        state
+            .edit_repertoire(color, edit)
+            .map_err(|e| format!("{:#}", e))
+    }
+
+    #[tauri::command]
+    pub fn learn_start(state: State<ChessState>) -> LearnStatus {
+        let mut state = state.0.lock().unwrap();
+        state.apply_pending_prep();
+        let app = &mut *state;
+        info!("Learning the {} repertoire", app.color);
+        let learn = LearnSession::new(
+            app.color,
+            app.db.repertoire().graph(app.color),
+            &app.progress,
+        );
+        let status = learn.status();
+        app.learn = Some(learn);
+        app.reset_board();
+        status
+    }
+
+    #[tauri::command]
+    pub fn learn_show(state: State<ChessState>) -> LearnStep {
+        let mut state = state.0.lock().unwrap();
+        let mut step = LearnStep {
+            board: String::new(),
+            san: None,
+            comments: vec![],
+        };
+        let next = state.learn.as_mut().and_then(|x| x.show_next());
+        if let Some((ply, node)) = next {
+            let node = state.db.repertoire().graph(state.color)[node].clone();
+            if ply == 0 {
+                state.reset_board_to(node.start().cloned());
+            }
+            state.play_san(&node.san, None);
+            step.san = Some(node.san.to_string());
+            step.comments = node.comments().to_vec();
+        }
+        step.board = state.game.board().to_string();
+        step
+    }
+
+    #[tauri::command]
+    pub fn learn_quiz(state: State<ChessState>) -> Round {
+        let mut state = state.0.lock().unwrap();
+        state.apply_pending_prep();
+        let app = &mut *state;
+        app.side = app.drill_config.side(app.color);
+        let mut game_state = app
+            .learn
+            .as_ref()
+            .and_then(|x| x.current_line())
+            .map(|line| {
+                app.db
+                    .repertoire()
+                    .start_line_drill(app.color, app.side, line)
+            });
+        // Lines from set up positions start from there rather than where the filter starts
+        match game_state.as_ref() {
+            Some(line) => app.reset_board_to(line.start_fen().cloned()),
+            None => app.reset_board(),
+        }
+        if let Some(learn) = app.learn.as_mut() {
+            learn.start_quiz();
+        }
+        if let Some(game_state) = game_state.as_mut() {
+            if !game_state.is_player_turn() {
+                if let Some(mv) = game_state.make_move(app.db.repertoire().graph(app.color)) {
+                    app.play_san(&mv, Some(MoveAssessment::InPrep));
+                }
+            }
+        }
+        app.game_state = game_state;
+        Round {
+            board: app.game.board().to_string(),
+            color: app.side.to_string(),
+        }
+    }
+
+    #[tauri::command]
+    pub fn learn_status(state: State<ChessState>) -> Option<LearnStatus> {
+        let state = state.0.lock().unwrap();
+        state.learn.as_ref().map(|x| x.status())
+    }
 }
 /*
 pub fn run() -> anyhow::Result<()> {
@@ -0,0 +1,262 @@
+//! Look for mistakes in a repertoire. Merging lots of study files can leave us with more than one
+//! answer to a position or lines that don't make sense, and the drills won't tell you about them.
+use crate::db::{line_key, path_to, Chapter, Prep, Priority};
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use serde::Serialize;
+use shakmaty::fen::Epd;
+use shakmaty::{Color, EnPassantMode, Position};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LintKind {
+    /// We have more than one primary move in a position, possibly reached by transposition
+    MultipleChoices { moves: Vec<String> },
+    /// The last move of the line can't be played
+    IllegalMove,
+    /// A first move that can't be played from the starting position, or the chapter's `FEN`
+    OrphanRoot,
+    /// The same line is in more than one chapter
+    DuplicateLine,
+    /// The line stops after our move, so we never learn what to expect from the opponent
+    EndsOnOurMove,
+}
+
+/// Where in the prep files a problem comes from
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Source {
+    pub file: PathBuf,
+    pub chapter: String,
+    /// Line in the file the chapter starts on
+    pub line: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct LintIssue {
+    /// Moves leading to the problem
+    pub moves: String,
+    pub kind: LintKind,
+    pub sources: Vec<Source>,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let moves = if self.moves.is_empty() {
+            "<start>"
+        } else {
+            self.moves.as_str()
+        };
+        match &self.kind {
+            LintKind::MultipleChoices { moves: choices } => write!(
+                f,
+                "More than one move for us after {}: {}",
+                moves,
+                choices.join(", ")
+            )?,
+            LintKind::IllegalMove => write!(f, "Illegal move at the end of {}", moves)?,
+            LintKind::OrphanRoot => write!(f, "{} can't be played from the start", moves)?,
+            LintKind::DuplicateLine => write!(f, "{} is in more than one chapter", moves)?,
+            LintKind::EndsOnOurMove => write!(f, "{} ends on our move", moves)?,
+        }
+        for source in &self.sources {
+            write!(f, "\n  {}", source.file.display())?;
+            if let Some(line) = source.line {
+                write!(f, ":{}", line)?;
+            }
+            write!(f, " ({})", source.chapter)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the prep for the `color` repertoire, issues are sorted by the moves they're found after.
+pub fn lint(color: Color, prep: &Prep) -> Vec<LintIssue> {
+    let openings = &prep.graph;
+    let starts = chapter_lines(&prep.chapters);
+    let sources = |nodes: &[NodeIndex]| {
+        nodes
+            .iter()
+            .flat_map(|n| openings[*n].chapters().iter())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|c| {
+                let chapter = prep.chapters.get(*c)?;
+                Some(Source {
+                    file: chapter.file.clone(),
+                    chapter: chapter.name.clone(),
+                    line: starts.get(c).copied(),
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    let key = |node: NodeIndex| line_key(openings, &path_to(openings, node));
+
+    let mut issues = vec![];
+    // For each position where we pick the move, the first line reaching it and our moves there
+    let mut decisions: HashMap<Epd, (String, BTreeMap<String, Vec<NodeIndex>>)> = HashMap::new();
+
+    let mut roots = openings.roots().to_vec();
+    roots.sort();
+    // Roots can be set up from different positions so each starts its own walk
+    let mut stack = roots
+        .into_iter()
+        .rev()
+        .map(|x| (None, openings[x].start_position(), vec![x]))
+        .collect::<Vec<_>>();
+    while let Some((parent, position, children)) = stack.pop() {
+        let epd = Epd::from_position(position.clone(), EnPassantMode::Legal);
+        for child in children {
+            let mv = match openings[child].san.san.to_move(&position) {
+                Ok(mv) => mv,
+                Err(_) => {
+                    issues.push(LintIssue {
+                        moves: key(child),
+                        kind: if parent.is_none() {
+                            LintKind::OrphanRoot
+                        } else {
+                            LintKind::IllegalMove
+                        },
+                        sources: sources(&[child]),
+                    });
+                    continue;
+                }
+            };
+            if position.turn() == color && openings[child].priority == Priority::Primary {
+                let (_, moves) = decisions
+                    .entry(epd.clone())
+                    .or_insert_with(|| (parent.map(key).unwrap_or_default(), BTreeMap::new()));
+                moves
+                    .entry(openings[child].san.to_string())
+                    .or_default()
+                    .push(child);
+            }
+            let mut next = position.clone();
+            next.play_unchecked(&mv);
+            let mut grandchildren = openings
+                .neighbors_directed(child, Direction::Outgoing)
+                .collect::<Vec<_>>();
+            if grandchildren.is_empty() {
+                if openings[child].chapters().len() > 1 {
+                    issues.push(LintIssue {
+                        moves: key(child),
+                        kind: LintKind::DuplicateLine,
+                        sources: sources(&[child]),
+                    });
+                }
+                if position.turn() == color {
+                    issues.push(LintIssue {
+                        moves: key(child),
+                        kind: LintKind::EndsOnOurMove,
+                        sources: sources(&[child]),
+                    });
+                }
+            }
+            grandchildren.sort();
+            stack.push((Some(child), next, grandchildren));
+        }
+    }
+
+    for (moves, choices) in decisions.into_values() {
+        if choices.len() > 1 {
+            let nodes = choices.values().flatten().copied().collect::<Vec<_>>();
+            issues.push(LintIssue {
+                moves,
+                kind: LintKind::MultipleChoices {
+                    moves: choices.into_keys().collect(),
+                },
+                sources: sources(&nodes),
+            });
+        }
+    }
+    issues.sort();
+    issues
+}
+
+/// The line each chapter starts on in its file, chapters are read in the order they're written so
+/// the nth chapter for a file is the nth game in it.
+fn chapter_lines(chapters: &[Chapter]) -> HashMap<usize, usize> {
+    let mut starts: HashMap<&PathBuf, Vec<usize>> = HashMap::new();
+    let mut seen: HashMap<&PathBuf, usize> = HashMap::new();
+    let mut res = HashMap::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let games = starts.entry(&chapter.file).or_insert_with(|| {
+            let text = fs::read_to_string(&chapter.file).unwrap_or_default();
+            let mut games = vec![];
+            let mut in_headers = false;
+            for (line, text) in text.lines().enumerate() {
+                let header = text.trim_start().starts_with('[');
+                if header && !in_headers {
+                    games.push(line + 1);
+                }
+                if !text.trim().is_empty() {
+                    in_headers = header;
+                }
+            }
+            games
+        });
+        let index = seen.entry(&chapter.file).or_insert(0);
+        if let Some(line) = games.get(*index) {
+            res.insert(i, *line);
+        }
+        *index += 1;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_problems() {
+        let folder =
+            std::env::temp_dir().join(format!("chess-driller-lint-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let file = folder.join("bad.pgn");
+        fs::write(
+            &file,
+            "[Event \"Open\"]\n\n1. e4 e5 2. Nf3 Nc6 *\n\n\
+             [Event \"Italian\"]\n\n1. e4 e5 2. Bc4 Nf6 *\n\n\
+             [Event \"Copy\"]\n\n1. e4 e5 2. Nf3 Nc6 *\n\n\
+             [Event \"Broken\"]\n\n1. e4 c5 2. Ke3 d6 *\n\n\
+             [Event \"Short\"]\n\n1. e4 d5 2. exd5 *\n\n\
+             [Event \"Wrong\"]\n\n1. Nf6 *\n",
+        )
+        .unwrap();
+        let prep = Prep::load(Color::White, std::slice::from_ref(&folder)).unwrap();
+        let issues = lint(Color::White, &prep);
+        let found = issues
+            .iter()
+            .map(|x| (x.moves.as_str(), &x.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("Nf6", &LintKind::OrphanRoot),
+                ("e4 c5 Ke3", &LintKind::IllegalMove),
+                ("e4 d5 exd5", &LintKind::EndsOnOurMove),
+                (
+                    "e4 e5",
+                    &LintKind::MultipleChoices {
+                        moves: vec!["Bc4".to_string(), "Nf3".to_string()]
+                    }
+                ),
+                ("e4 e5 Nf3 Nc6", &LintKind::DuplicateLine),
+            ]
+        );
+
+        let duplicate = &issues[4];
+        let lines = duplicate
+            .sources
+            .iter()
+            .map(|x| (x.chapter.as_str(), x.line))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![("Open", Some(1)), ("Copy", Some(9))]);
+        assert!(duplicate.to_string().contains("bad.pgn:9 (Copy)"));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+use chess_driller::cli::{self, Args};
+use clap::Parser;
 use std::env;
 use tracing::info;
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::{Layer, Registry};
 
-fn main() {
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
     let filter = match env::var("RUST_LOG") {
         Ok(_) => EnvFilter::from_env("RUST_LOG"),
         _ => EnvFilter::new("chess_driller=info"),
@@ -16,7 +20,12 @@ fn main() {
     let subscriber = filter.and_then(fmt).with_subscriber(Registry::default());
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
+    if let Some(command) = args.command {
+        return cli::run(command, args.profile.as_deref(), args.config.as_deref());
+    }
+
     info!("Starting chess driller");
 
-    chess_driller::launch();
+    chess_driller::launch(args.config, args.profile.as_deref());
+    Ok(())
 }
@@ -0,0 +1,625 @@
+//! A reference book of what strong players play, imported from large PGN dumps like the lichess
+//! database or TWIC. Games are streamed and only the first moves of each are kept, as counts for
+//! each position and move, so the book stays small enough to keep on disk and in memory.
+use crate::config::Config;
+use crate::parallel::parallel_map;
+use crate::polyglot::{decode_move, encode_move, hash};
+use crate::tree::OpeningGraph;
+use anyhow::Context;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use pgn_reader::{BufferedReader, RawHeader, SanPlus, Skip, Visitor};
+use serde::Serialize;
+use shakmaty::{Chess, Color, Move, Position};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+/// Start of a masters book file, so other files aren't mistaken for one
+const MAGIC: &[u8; 8] = b"CDMASTR2";
+
+/// Position key, move, games, white wins and draws
+const ENTRY_SIZE: usize = 34;
+
+/// Log progress every this many games when importing
+const PROGRESS_GAMES: u64 = 100_000;
+
+/// Roughly how much PGN each thread imports at a time
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Counts for one move from a position. The move is in the polyglot encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MasterEntry {
+    pub key: u64,
+    pub mv: u16,
+    pub games: u64,
+    pub white_wins: u64,
+    pub draws: u64,
+}
+
+impl MasterEntry {
+    pub fn black_wins(&self) -> u64 {
+        self.games - self.white_wins - self.draws
+    }
+}
+
+/// A move from a position and how it went for the players who chose it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MasterMove {
+    pub san: String,
+    pub games: u64,
+    pub white_wins: u64,
+    pub draws: u64,
+    pub black_wins: u64,
+}
+
+/// Entries are kept sorted by position and move so positions can be found with a binary search
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MastersBook {
+    entries: Vec<MasterEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportSettings {
+    /// Only moves up to this ply of each game are kept
+    pub max_plies: usize,
+    /// Both players need at least this rating, games without ratings are skipped unless it's 0
+    pub min_rating: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub games: u64,
+    /// Games left out for their ratings, a set up position or no result
+    pub skipped: u64,
+}
+
+impl MastersBook {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position_entries(&self, position: &Chess) -> &[MasterEntry] {
+        let key = hash(position);
+        let start = self.entries.partition_point(|x| x.key < key);
+        let end = self.entries.partition_point(|x| x.key <= key);
+        &self.entries[start..end]
+    }
+
+    /// The moves played from `position`, most played first
+    pub fn moves(&self, position: &Chess) -> Vec<(Move, MasterEntry)> {
+        let mut res = self
+            .position_entries(position)
+            .iter()
+            .filter_map(|x| decode_move(position, x.mv).map(|mv| (mv, *x)))
+            .collect::<Vec<_>>();
+        res.sort_by_key(|x| Reverse(x.1.games));
+        res
+    }
+
+    /// How many games `mv` was played in from `position`
+    pub fn games(&self, position: &Chess, mv: &Move) -> u64 {
+        let mv = encode_move(mv);
+        self.position_entries(position)
+            .iter()
+            .find(|x| x.mv == mv)
+            .map(|x| x.games)
+            .unwrap_or(0)
+    }
+
+    /// The moves from `position` written out for the explorer
+    pub fn stats(&self, position: &Chess) -> Vec<MasterMove> {
+        self.moves(position)
+            .into_iter()
+            .map(|(mv, entry)| MasterMove {
+                san: SanPlus::from_move(position.clone(), &mv).to_string(),
+                games: entry.games,
+                white_wins: entry.white_wins,
+                draws: entry.draws,
+                black_wins: entry.black_wins(),
+            })
+            .collect()
+    }
+
+    fn from_counts(counts: HashMap<(u64, u16), MasterEntry>) -> Self {
+        let mut entries = counts.into_values().collect::<Vec<_>>();
+        entries.sort_by_key(|x| (x.key, x.mv));
+        Self { entries }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        if !data.starts_with(MAGIC) || !(data.len() - MAGIC.len()).is_multiple_of(ENTRY_SIZE) {
+            anyhow::bail!("{} isn't a masters book", path.display());
+        }
+        let entries = data[MAGIC.len()..]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|x| MasterEntry {
+                key: u64::from_be_bytes(x[0..8].try_into().unwrap()),
+                mv: u16::from_be_bytes(x[8..10].try_into().unwrap()),
+                games: u64::from_be_bytes(x[10..18].try_into().unwrap()),
+                white_wins: u64::from_be_bytes(x[18..26].try_into().unwrap()),
+                draws: u64::from_be_bytes(x[26..34].try_into().unwrap()),
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// Save the book, it's written to a temporary file and moved into place so a book being
+    /// used is never left half written
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut data = Vec::with_capacity(MAGIC.len() + self.entries.len() * ENTRY_SIZE);
+        data.extend_from_slice(MAGIC);
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.key.to_be_bytes());
+            data.extend_from_slice(&entry.mv.to_be_bytes());
+            data.extend_from_slice(&entry.games.to_be_bytes());
+            data.extend_from_slice(&entry.white_wins.to_be_bytes());
+            data.extend_from_slice(&entry.draws.to_be_bytes());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, data).with_context(|| format!("writing {}", temp.display()))?;
+        fs::rename(&temp, path).with_context(|| format!("replacing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn add_counts(counts: &mut HashMap<(u64, u16), MasterEntry>, entry: &MasterEntry) {
+    let total = counts.entry((entry.key, entry.mv)).or_insert(MasterEntry {
+        key: entry.key,
+        mv: entry.mv,
+        ..Default::default()
+    });
+    total.games = total.games.saturating_add(entry.games);
+    total.white_wins = total.white_wins.saturating_add(entry.white_wins);
+    total.draws = total.draws.saturating_add(entry.draws);
+}
+
+/// Where the masters book is kept for a profile
+pub fn masters_path(config: &Config) -> PathBuf {
+    config.data_dir().join("masters.bin")
+}
+
+/// Load the profile's masters book if one has been imported
+pub fn load_masters(config: &Config) -> Option<MastersBook> {
+    let path = masters_path(config);
+    if !path.exists() {
+        return None;
+    }
+    match MastersBook::load(&path) {
+        Ok(book) => {
+            info!("Loaded {} moves from the masters book", book.len());
+            Some(book)
+        }
+        Err(e) => {
+            error!("Couldn't load the masters book: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Builds a book from any number of PGNs
+pub struct MastersImporter {
+    settings: ImportSettings,
+    counts: HashMap<(u64, u16), MasterEntry>,
+    summary: ImportSummary,
+}
+
+impl MastersImporter {
+    pub fn new(settings: ImportSettings) -> Self {
+        Self {
+            settings,
+            counts: HashMap::new(),
+            summary: ImportSummary::default(),
+        }
+    }
+
+    /// Read the games in a PGN, it's streamed so it can be as big as you like
+    pub fn add_pgn(&mut self, pgn: impl io::Read) -> anyhow::Result<()> {
+        let mut reader = BufferedReader::new(pgn);
+        let mut visitor = MastersVisitor {
+            importer: self,
+            position: Chess::default(),
+            moves: vec![],
+            ratings: [None, None],
+            result: None,
+            skip: false,
+            ended: false,
+        };
+        while reader.has_more()? {
+            reader.read_game(&mut visitor)?;
+        }
+        Ok(())
+    }
+
+    /// Read PGNs on up to `threads` threads. Each PGN is split between games into chunks that are
+    /// imported on their own and added up here, only a few chunks are read ahead so big dumps and
+    /// stdin are still streamed.
+    pub fn add_pgns<R: io::BufRead>(
+        &mut self,
+        pgns: impl IntoIterator<Item = anyhow::Result<R>>,
+        threads: usize,
+    ) -> anyhow::Result<()> {
+        let mut chunks =
+            pgns.into_iter()
+                .flat_map(|pgn| -> Box<dyn Iterator<Item = anyhow::Result<Vec<u8>>>> {
+                    match pgn {
+                        Ok(pgn) => Box::new(PgnChunks::new(pgn, CHUNK_SIZE).map(|x| Ok(x?))),
+                        Err(e) => Box::new(std::iter::once(Err(e))),
+                    }
+                });
+        loop {
+            let batch = chunks
+                .by_ref()
+                .take(threads * 2)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let imported = parallel_map(threads, &batch, |chunk| {
+                let mut importer = MastersImporter::new(self.settings);
+                importer.add_pgn(chunk.as_slice())?;
+                anyhow::Ok(importer)
+            });
+            for importer in imported {
+                let importer = importer?;
+                for entry in importer.counts.values() {
+                    add_counts(&mut self.counts, entry);
+                }
+                self.summary.games += importer.summary.games;
+                self.summary.skipped += importer.summary.skipped;
+            }
+            info!("Imported {} games", self.summary.games);
+        }
+    }
+
+    /// Add the counts from a book imported before
+    pub fn add_book(&mut self, book: &MastersBook) {
+        for entry in &book.entries {
+            add_counts(&mut self.counts, entry);
+        }
+    }
+
+    pub fn finish(self) -> (MastersBook, ImportSummary) {
+        (MastersBook::from_counts(self.counts), self.summary)
+    }
+}
+
+/// Splits a PGN into pieces of at least `size` bytes, ending before a game's tags so each piece
+/// holds whole games
+struct PgnChunks<R> {
+    reader: R,
+    size: usize,
+    /// The line that started the next piece
+    next: Vec<u8>,
+    /// The last line with anything on it was a tag
+    in_tags: bool,
+    /// Inside a `{}` comment, these don't nest and tags can't start in one
+    in_comment: bool,
+}
+
+impl<R: io::BufRead> PgnChunks<R> {
+    fn new(reader: R, size: usize) -> Self {
+        Self {
+            reader,
+            size,
+            next: vec![],
+            in_tags: false,
+            in_comment: false,
+        }
+    }
+}
+
+impl<R: io::BufRead> Iterator for PgnChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = std::mem::take(&mut self.next);
+        let mut line = vec![];
+        loop {
+            line.clear();
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => return (!chunk.is_empty()).then_some(Ok(chunk)),
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            let trimmed = line.trim_ascii_start();
+            let tag = !self.in_comment
+                && trimmed.first() == Some(&b'[')
+                && trimmed.get(1).is_some_and(|x| x.is_ascii_alphabetic());
+            if tag && !self.in_tags && chunk.len() >= self.size {
+                self.in_tags = true;
+                self.next.extend_from_slice(&line);
+                return Some(Ok(chunk));
+            }
+            if tag {
+                self.in_tags = true;
+            } else if !trimmed.is_empty() {
+                self.in_tags = false;
+                for c in trimmed {
+                    match c {
+                        b'}' => self.in_comment = false,
+                        _ if self.in_comment => {}
+                        b'{' => self.in_comment = true,
+                        // The rest of the line is a comment
+                        b';' => break,
+                        _ => {}
+                    }
+                }
+            }
+            chunk.extend_from_slice(&line);
+        }
+    }
+}
+
+struct MastersVisitor<'a> {
+    importer: &'a mut MastersImporter,
+    position: Chess,
+    /// Position key and move for each ply kept
+    moves: Vec<(u64, u16)>,
+    ratings: [Option<u16>; 2],
+    /// Wins for white, black or a draw
+    result: Option<Option<Color>>,
+    skip: bool,
+    /// The game had an illegal move or went past the ply limit
+    ended: bool,
+}
+
+impl Visitor for MastersVisitor<'_> {
+    type Result = ();
+
+    fn begin_game(&mut self) {
+        self.position = Chess::default();
+        self.moves.clear();
+        self.ratings = [None, None];
+        self.result = None;
+        self.skip = false;
+        self.ended = false;
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader) {
+        let rating = || value.decode_utf8_lossy().parse().ok();
+        match key {
+            b"WhiteElo" => self.ratings[0] = rating(),
+            b"BlackElo" => self.ratings[1] = rating(),
+            b"Result" => {
+                self.result = match value.as_bytes() {
+                    b"1-0" => Some(Some(Color::White)),
+                    b"0-1" => Some(Some(Color::Black)),
+                    b"1/2-1/2" => Some(None),
+                    _ => None,
+                }
+            }
+            // Only games from the usual starting position go in the book
+            b"FEN" => self.skip = true,
+            _ => {}
+        }
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        let min_rating = self.importer.settings.min_rating;
+        let rated = self.ratings.iter().all(|x| x.unwrap_or(0) >= min_rating);
+        self.skip |= !rated || self.result.is_none();
+        Skip(self.skip)
+    }
+
+    fn san(&mut self, san_plus: SanPlus) {
+        if self.ended || self.moves.len() >= self.importer.settings.max_plies {
+            self.ended = true;
+            return;
+        }
+        match san_plus.san.to_move(&self.position) {
+            Ok(mv) => {
+                self.moves.push((hash(&self.position), encode_move(&mv)));
+                self.position.play_unchecked(&mv);
+            }
+            Err(_) => self.ended = true,
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true)
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        let summary = &mut self.importer.summary;
+        if self.skip {
+            summary.skipped += 1;
+            return;
+        }
+        summary.games += 1;
+        if summary.games.is_multiple_of(PROGRESS_GAMES) {
+            info!("Imported {} games", summary.games);
+        }
+        let result = self.result.flatten();
+        let draw = self.result == Some(None);
+        for (key, mv) in self.moves.drain(..) {
+            add_counts(
+                &mut self.importer.counts,
+                &MasterEntry {
+                    key,
+                    mv,
+                    games: 1,
+                    white_wins: (result == Some(Color::White)) as u64,
+                    draws: draw as u64,
+                },
+            );
+        }
+    }
+}
+
+/// How often masters played each move in the repertoire, so the computer can pick its moves in
+/// the same proportions
+pub fn masters_weights(openings: &OpeningGraph, book: &MastersBook) -> HashMap<NodeIndex, u64> {
+    let mut weights = HashMap::new();
+    let mut stack = openings
+        .roots()
+        .iter()
+        .copied()
+        .map(|x| (x, openings[x].start_position()))
+        .collect::<Vec<_>>();
+    while let Some((node, position)) = stack.pop() {
+        let Ok(mv) = openings[node].san.san.to_move(&position) else {
+            continue;
+        };
+        let games = book.games(&position, &mv);
+        weights.insert(node, games);
+        let mut next = position;
+        next.play_unchecked(&mv);
+        for child in openings.neighbors_directed(node, Direction::Outgoing) {
+            stack.push((child, next.clone()));
+        }
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{find_line, MoveAssessment, OpeningDatabase};
+    use crate::layers::{Layer, LayeredDatabase};
+    use std::collections::BTreeMap;
+
+    fn game(white: u16, black: u16, result: &str, moves: &str) -> String {
+        format!(
+            "[WhiteElo \"{}\"]\n[BlackElo \"{}\"]\n[Result \"{}\"]\n\n{} {}\n\n",
+            white, black, result, moves, result
+        )
+    }
+
+    fn san(position: &Chess, san: &str) -> Move {
+        SanPlus::from_ascii(san.as_bytes())
+            .unwrap()
+            .san
+            .to_move(position)
+            .unwrap()
+    }
+
+    #[test]
+    fn pgn_chunks() {
+        let first =
+            "[Event \"a\"]\n[Result \"*\"]\n\n1. e4 { a comment\n[Event \"b\"] still } *\n\n";
+        // Comments don't nest and `{` means nothing in a `;` comment
+        let second = "[Event \"c\"]\n[Result \"*\"]\n\n1. d4 { a { b } ; c {\n[%clk 0:01:00] } *\n";
+        let third = "[Event \"d\"]\n[Result \"*\"]\n\n1. c4 *\n";
+        let pgn = format!("{}{}{}", first, second, third);
+        let chunks = PgnChunks::new(pgn.as_bytes(), 1)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            chunks,
+            [first.as_bytes(), second.as_bytes(), third.as_bytes()]
+        );
+        let chunks = PgnChunks::new(pgn.as_bytes(), pgn.len())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(chunks, [pgn.as_bytes()]);
+    }
+
+    #[test]
+    fn import_masters() {
+        let mut pgn = String::new();
+        for _ in 0..3 {
+            pgn.push_str(&game(2600, 2500, "1-0", "1. e4 e5 2. Nc3 Nf6 3. f4"));
+        }
+        pgn.push_str(&game(2400, 2450, "1/2-1/2", "1. e4 c5 2. Nf3 (2. Nc3) d6"));
+        // Too weak, no result or not from the start
+        pgn.push_str(&game(2600, 1500, "0-1", "1. d4 d5"));
+        pgn.push_str(&game(2600, 2600, "*", "1. d4 d5"));
+        pgn.push_str("[FEN \"8/8/8/8/8/8/8/K6k w - - 0 1\"]\n[Result \"1-0\"]\n\n1. Kb1 1-0\n\n");
+
+        let settings = ImportSettings {
+            max_plies: 4,
+            min_rating: 2200,
+        };
+        let mut importer = MastersImporter::new(settings);
+        importer.add_pgn(pgn.as_bytes()).unwrap();
+        let (book, summary) = importer.finish();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                games: 4,
+                skipped: 3
+            }
+        );
+
+        let start = Chess::default();
+        let first = book.stats(&start);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].san, "e4");
+        assert_eq!(
+            (first[0].games, first[0].white_wins, first[0].draws),
+            (4, 3, 1)
+        );
+        let mut position = start.clone();
+        for mv in ["e4", "e5", "Nc3", "Nf6"] {
+            let mv = san(&position, mv);
+            position.play_unchecked(&mv);
+        }
+        // Past the ply limit
+        assert!(book.moves(&position).is_empty());
+        let mut sicilian = start.clone();
+        for mv in ["e4", "c5"] {
+            let mv = san(&sicilian, mv);
+            sicilian.play_unchecked(&mv);
+        }
+        // Variations aren't counted
+        let nf3 = san(&sicilian, "Nf3");
+        let nc3 = san(&sicilian, "Nc3");
+        assert_eq!(book.games(&sicilian, &nf3), 1);
+        assert_eq!(book.games(&sicilian, &nc3), 0);
+
+        let path =
+            std::env::temp_dir().join(format!("chess-driller-masters-{}", std::process::id()));
+        book.save(&path).unwrap();
+        let loaded = MastersBook::load(&path).unwrap();
+        assert_eq!(loaded, book);
+        fs::remove_file(&path).unwrap();
+
+        // Read on a few threads it adds up the same, and on top of a book from before
+        let mut importer = MastersImporter::new(settings);
+        let pgns = [pgn.as_bytes(), pgn.as_bytes()].map(anyhow::Ok);
+        importer.add_pgns(pgns, 3).unwrap();
+        importer.add_book(&loaded);
+        let (combined, summary) = importer.finish();
+        assert_eq!((summary.games, summary.skipped), (8, 6));
+        assert_eq!(combined.stats(&start)[0].games, 12);
+        assert_eq!(combined.games(&sicilian, &nf3), 3);
+
+        // The computer plays 1... e5 as that's all masters play against 1. e4 in the book
+        let db = OpeningDatabase::load(std::path::Path::new("prep")).unwrap();
+        let graph = db.graph(Color::Black);
+        let weights = masters_weights(graph, &book);
+        let e4 = SanPlus::from_ascii(b"e4").unwrap();
+        assert_eq!(
+            weights[&find_line(graph, None, std::slice::from_ref(&e4)).unwrap()],
+            4
+        );
+        let graph = db.graph(Color::White);
+        let weights = masters_weights(graph, &book);
+        for _ in 0..10 {
+            let mut state = db.start_drill(Color::White, &[]).unwrap();
+            state.set_move_weights(weights.clone());
+            assert_eq!(state.apply_move(&e4, graph), MoveAssessment::InPrep);
+            assert_eq!(state.make_move(graph).unwrap().to_string(), "e5");
+        }
+
+        // And the explorer shows the masters' moves next to the prep
+        let mut layers = LayeredDatabase::new(db);
+        layers.set_masters(Some(book));
+        let first = layers.lookup(Color::White, &start);
+        assert_eq!(first[0].san, "e4");
+        assert!(first[0].in_prep);
+        assert_eq!(first[0].games, BTreeMap::from([(Layer::Masters, 4)]));
+    }
+}
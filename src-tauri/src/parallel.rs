@@ -0,0 +1,46 @@
+//! Spreading work like reading PGNs over all the cores. Results come back in the same order as the
+//! work went in, so nothing depends on which thread finished first.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// How many threads to use, one per core
+pub fn threads() -> usize {
+    thread::available_parallelism().map_or(1, |x| x.get())
+}
+
+/// Call `f` on each item on up to `threads` threads. Items are handed out one at a time so a big
+/// file doesn't hold up the ones queued behind it.
+pub fn parallel_map<T: Sync, R: Send>(
+    threads: usize,
+    items: &[T],
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let threads = threads.min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break;
+                        };
+                        done.push((i, f(item)));
+                    }
+                    done
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|x| x.join().expect("worker thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|x| x.0);
+    results.into_iter().map(|x| x.1).collect()
+}
@@ -0,0 +1,363 @@
+//! Add lines to PGN files in place. Only the text for the new moves is inserted, everything else
+//! in the file (comments, formatting, other chapters) is left exactly as it was.
+use crate::db::{normalise_start, ply_offset, setup_position};
+use pgn_reader::SanPlus;
+use shakmaty::fen::Fen;
+
+/// A move found in the movetext
+#[derive(Debug)]
+struct MoveEntry {
+    /// All the moves up to and including this one
+    path: Vec<String>,
+    /// Where a variation replacing this move can be written, after any comments or NAGs
+    after: usize,
+    /// Index of the line (main line or variation) the move is in
+    line: usize,
+}
+
+/// A game in the file, offsets are into the whole file
+#[derive(Debug, Default)]
+struct GameText {
+    moves: Vec<MoveEntry>,
+    /// For each line in the game where more moves can be added on the end
+    line_ends: Vec<usize>,
+    /// The game's `FEN` header
+    start: Option<Fen>,
+}
+
+/// Add `line` to the PGN text. It's put into the game sharing the most moves with it as a
+/// variation, if no game shares its first move a new one is added with the `event` name. Only games
+/// set up from the `start` FEN are used, or games from the usual position if it's `None`.
+pub fn add_line(text: &str, start: Option<&Fen>, line: &[SanPlus], event: &str) -> String {
+    let line = line.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    if line.is_empty() {
+        return text.to_string();
+    }
+    let games = parse_games(text);
+    let first_ply = start
+        .and_then(|x| setup_position(x).ok())
+        .map(|x| ply_offset(&x))
+        .unwrap_or(0);
+
+    let best = games
+        .iter()
+        .filter(|game| game.start.as_ref() == start)
+        .map(|game| {
+            let matched = game
+                .moves
+                .iter()
+                .filter(|m| line.starts_with(&m.path))
+                .map(|m| m.path.len())
+                .max()
+                .unwrap_or(0);
+            (matched, game)
+        })
+        .reduce(|best, next| if next.0 > best.0 { next } else { best });
+
+    let (matched, game) = match best {
+        Some(best) if best.0 > 0 => best,
+        _ => return append_game(text, start, &line, event),
+    };
+    if matched == line.len() {
+        return text.to_string();
+    }
+
+    // If the game carries on from where we match we add an alternative to that next move,
+    // otherwise we've hit the end of a line and can just carry on with it
+    let next_move = game
+        .moves
+        .iter()
+        .find(|m| m.path.len() == matched + 1 && m.path[..matched] == line[..matched]);
+    let (offset, insert) = match next_move {
+        Some(next) => (
+            next.after,
+            format!(
+                " ({})",
+                movetext(&line[matched..], first_ply + matched, true)
+            ),
+        ),
+        None => {
+            let last = game
+                .moves
+                .iter()
+                .find(|m| m.path == line[..matched])
+                .expect("matched a move so it must exist");
+            (
+                game.line_ends[last.line],
+                format!(
+                    " {}",
+                    movetext(&line[matched..], first_ply + matched, false)
+                ),
+            )
+        }
+    };
+    let mut res = text.to_string();
+    res.insert_str(offset, &insert);
+    res
+}
+
+fn append_game(text: &str, start: Option<&Fen>, line: &[String], event: &str) -> String {
+    let mut res = text.trim_end().to_string();
+    if !res.is_empty() {
+        res.push_str("\n\n");
+    }
+    res.push_str(&format!("[Event \"{}\"]\n", event.replace('"', "'")));
+    let mut first_ply = 0;
+    if let Some(fen) = start {
+        res.push_str(&format!("[SetUp \"1\"]\n[FEN \"{}\"]\n", fen));
+        first_ply = setup_position(fen).map(|x| ply_offset(&x)).unwrap_or(0);
+    }
+    res.push_str(&format!(
+        "[Result \"*\"]\n\n{} *\n",
+        movetext(line, first_ply, true)
+    ));
+    res
+}
+
+/// Write out moves starting at `ply`. Black's first move needs a number if it starts a variation
+fn movetext(moves: &[String], ply: usize, number_first: bool) -> String {
+    let mut res = vec![];
+    for (i, mv) in moves.iter().enumerate() {
+        let ply = ply + i;
+        let number = ply / 2 + 1;
+        if ply.is_multiple_of(2) {
+            res.push(format!("{}. {}", number, mv));
+        } else if i == 0 && number_first {
+            res.push(format!("{}... {}", number, mv));
+        } else {
+            res.push(mv.clone());
+        }
+    }
+    res.join(" ")
+}
+
+/// Normalise a SAN token so `h4!` and `h4` compare the same
+fn parse_san(token: &str) -> Option<String> {
+    let token = token.trim_end_matches(['!', '?']);
+    SanPlus::from_ascii(token.as_bytes())
+        .ok()
+        .map(|x| x.to_string())
+}
+
+fn parse_games(text: &str) -> Vec<GameText> {
+    let bytes = text.as_bytes();
+    let mut games = vec![];
+    let mut game: Option<GameText> = None;
+    // Each entry is the path before the line started and the index of the line
+    let mut stack: Vec<(Vec<String>, usize)> = vec![];
+    let mut path: Vec<String> = vec![];
+    let mut i = 0;
+    let mut line_start = true;
+    // Headers come before the game they belong to is started
+    let mut start = None;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if line_start && c == b'[' {
+            // A header means any game without a result has finished
+            games.extend(game.take());
+            let header_start = i;
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            if let Some(fen) = text[header_start..i]
+                .trim()
+                .strip_prefix("[FEN \"")
+                .and_then(|x| x.strip_suffix("\"]"))
+            {
+                start = Fen::from_ascii(fen.as_bytes())
+                    .ok()
+                    .and_then(normalise_start);
+            }
+            continue;
+        }
+        // `%` only escapes the rest of the line in the first column
+        let escape = line_start && c == b'%';
+        line_start = c == b'\n';
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let g = game.get_or_insert_with(|| {
+            path.clear();
+            stack.clear();
+            stack.push((vec![], 0));
+            GameText {
+                moves: vec![],
+                line_ends: vec![i],
+                start: start.take(),
+            }
+        });
+        let line = stack.last().map(|x| x.1).unwrap_or(0);
+        match c {
+            b'{' | b'$' => {
+                if c == b'{' {
+                    while i < bytes.len() && bytes[i] != b'}' {
+                        i += 1;
+                    }
+                    i = (i + 1).min(bytes.len());
+                } else {
+                    i += 1;
+                    while i < bytes.len() && bytes[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                g.line_ends[line] = i;
+                if let Some(last) = g.moves.last_mut() {
+                    if last.path == path {
+                        last.after = i;
+                    }
+                }
+            }
+            b';' | b'%' if c == b';' || escape => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'(' => {
+                stack.push((path.clone(), g.line_ends.len()));
+                g.line_ends.push(i + 1);
+                path.pop();
+                i += 1;
+            }
+            b')' => {
+                i += 1;
+                if stack.len() > 1 {
+                    if let Some((parent, _)) = stack.pop() {
+                        path = parent;
+                    }
+                }
+                // Moves carrying on this line have to go after the variation
+                let parent = stack.last().map(|x| x.1).unwrap_or(0);
+                g.line_ends[parent] = i;
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len()
+                    && !bytes[i].is_ascii_whitespace()
+                    && !b"{}();$".contains(&bytes[i])
+                {
+                    i += 1;
+                }
+                let token = &text[start..i];
+                if matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*") {
+                    games.extend(game.take());
+                } else if let Some(san) =
+                    parse_san(token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.'))
+                {
+                    path.push(san);
+                    g.moves.push(MoveEntry {
+                        path: path.clone(),
+                        after: i,
+                        line,
+                    });
+                    g.line_ends[line] = i;
+                }
+            }
+        }
+    }
+    games.extend(game);
+    games
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{line_key, lines, Prep};
+    use shakmaty::Color;
+    use std::fs;
+
+    fn sans(moves: &str) -> Vec<SanPlus> {
+        moves
+            .split_whitespace()
+            .map(|x| SanPlus::from_ascii(x.as_bytes()).unwrap())
+            .collect()
+    }
+
+    const PGN: &str = "[Event \"Test\"]\n\n1. e4 e5 2. Nf3 { Main line } (2. Nc3 Nf6) 2... Nc6 *\n";
+
+    #[test]
+    fn add_variation() {
+        let res = add_line(PGN, None, &sans("e4 e5 Nf3 Nf6"), "Additions");
+        assert_eq!(
+            res,
+            "[Event \"Test\"]\n\n1. e4 e5 2. Nf3 { Main line } (2. Nc3 Nf6) 2... Nc6 (2... Nf6) *\n"
+        );
+        let res = add_line(PGN, None, &sans("e4 c5"), "Additions");
+        assert_eq!(
+            res,
+            "[Event \"Test\"]\n\n1. e4 e5 (1... c5) 2. Nf3 { Main line } (2. Nc3 Nf6) 2... Nc6 *\n"
+        );
+    }
+
+    #[test]
+    fn extend_lines() {
+        let res = add_line(PGN, None, &sans("e4 e5 Nc3 Nf6 f4"), "Additions");
+        assert_eq!(
+            res,
+            "[Event \"Test\"]\n\n1. e4 e5 2. Nf3 { Main line } (2. Nc3 Nf6 3. f4) 2... Nc6 *\n"
+        );
+        let res = add_line(PGN, None, &sans("e4 e5 Nf3 Nc6 Bb5"), "Additions");
+        assert_eq!(
+            res,
+            "[Event \"Test\"]\n\n1. e4 e5 2. Nf3 { Main line } (2. Nc3 Nf6) 2... Nc6 3. Bb5 *\n"
+        );
+    }
+
+    #[test]
+    fn existing_and_new_games() {
+        assert_eq!(add_line(PGN, None, &sans("e4 e5 Nc3"), "Additions"), PGN);
+        assert_eq!(
+            add_line("", None, &sans("d4 d5"), "Additions"),
+            "[Event \"Additions\"]\n[Result \"*\"]\n\n1. d4 d5 *\n"
+        );
+        // Nothing starts with d4 so it gets its own game rather than a variation of e4
+        assert_eq!(
+            add_line(PGN, None, &sans("d4 d5"), "Additions"),
+            format!(
+                "{}\n[Event \"Additions\"]\n[Result \"*\"]\n\n1. d4 d5 *\n",
+                PGN
+            )
+        );
+    }
+
+    #[test]
+    fn results_and_escapes() {
+        // `10-0` isn't a result, a `%` after the start of a line isn't an escape
+        let pgn = "[Event \"Test\"]\n\n1. e4 e5 10-0 2. Nf3 % Nc6\n%escaped 3. Bc4\n1/2-1/2\n";
+        let res = add_line(pgn, None, &sans("e4 e5 Nf3 Nc6 Bb5"), "Additions");
+        assert_eq!(
+            res,
+            "[Event \"Test\"]\n\n1. e4 e5 10-0 2. Nf3 % Nc6 3. Bb5\n%escaped 3. Bc4\n1/2-1/2\n"
+        );
+    }
+
+    #[test]
+    fn added_lines_load() {
+        let text = fs::read_to_string(
+            "prep/white/lichess_study_vienna-repertoire_vienna-gambit-main-line_by_anomality_2021.02.18.pgn",
+        )
+        .unwrap();
+        // Nxd2 ends a line which has a variation after it
+        let line = "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5 d4 Bb4 Bd2 Nxd2 Qxd2";
+        let text = add_line(&text, None, &sans(line), "Additions");
+        assert!(text.contains("(7... Bxc3 8. bxc3 c5 9. Bd3) 8. Qxd2)"));
+
+        let folder =
+            std::env::temp_dir().join(format!("chess-driller-added-lines-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("vienna.pgn"), text).unwrap();
+        let graph = Prep::load(Color::White, std::slice::from_ref(&folder))
+            .unwrap()
+            .graph;
+        fs::remove_dir_all(&folder).unwrap();
+        let lines = lines(&graph)
+            .iter()
+            .map(|x| line_key(&graph, x))
+            .collect::<Vec<_>>();
+        assert_eq!(lines.iter().filter(|x| *x == line).count(), 1);
+        assert!(lines
+            .iter()
+            .any(|x| x == "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5 d4 Bb4 Bd2 Bxc3 bxc3 c5 Bd3"));
+    }
+}
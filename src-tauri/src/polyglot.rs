@@ -0,0 +1,278 @@
+//! Read and write Polyglot `.bin` opening books so the repertoire can be used by engines and
+//! other GUIs, and books can be used as prep.
+use crate::db::{OpeningNode, Priority};
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use pgn_reader::SanPlus;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{Chess, Color, EnPassantMode, Move, Position, Role, Square};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Books can have cycles and huge trees so we stop reading lines after this many moves
+const BOOK_PLIES: usize = 40;
+
+/// Weight given to primary moves when weighting by priority, alternatives get a tenth of it
+const PRIMARY_WEIGHT: u16 = 100;
+
+/// How to weight the moves in an exported book
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BookWeights {
+    /// Number of chapters the move is in
+    #[default]
+    Frequency,
+    /// Primary moves are weighted well above alternatives
+    Priority,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BookEntry {
+    pub key: u64,
+    pub mv: u16,
+    pub weight: u16,
+    pub learn: u32,
+}
+
+/// Every move in the graph as book entries sorted by key, moves in the same position are sorted
+/// by weight with the main line first if they're the same.
+pub fn export_book(openings: &OpeningGraph, weights: BookWeights) -> Vec<BookEntry> {
+    // Transpositions can give us the same move more than once
+    let mut moves: BTreeMap<(u64, u16), (usize, u16)> = BTreeMap::new();
+    let mut roots = openings.roots().to_vec();
+    roots.sort();
+    let mut stack = roots
+        .into_iter()
+        .rev()
+        .map(|x| (openings[x].start_position(), vec![x]))
+        .collect::<Vec<_>>();
+    while let Some((position, children)) = stack.pop() {
+        let key = hash(&position);
+        for child in children {
+            let node = &openings[child];
+            let Ok(mv) = node.san.san.to_move(&position) else {
+                continue;
+            };
+            let weight = match weights {
+                BookWeights::Frequency => node.chapters().len().clamp(1, u16::MAX as usize) as u16,
+                BookWeights::Priority if node.priority == Priority::Primary => PRIMARY_WEIGHT,
+                BookWeights::Priority => PRIMARY_WEIGHT / 10,
+            };
+            let entry = moves
+                .entry((key, encode_move(&mv)))
+                .or_insert((child.index(), 0));
+            entry.1 = match weights {
+                BookWeights::Frequency => entry.1.saturating_add(weight),
+                BookWeights::Priority => entry.1.max(weight),
+            };
+
+            let mut next = position.clone();
+            next.play_unchecked(&mv);
+            let mut grandchildren = openings
+                .neighbors_directed(child, Direction::Outgoing)
+                .collect::<Vec<_>>();
+            grandchildren.sort();
+            stack.push((next, grandchildren));
+        }
+    }
+
+    let mut entries = moves
+        .into_iter()
+        .map(|((key, mv), (order, weight))| {
+            (
+                order,
+                BookEntry {
+                    key,
+                    mv,
+                    weight,
+                    learn: 0,
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|(a_order, a), (b_order, b)| {
+        a.key
+            .cmp(&b.key)
+            .then(b.weight.cmp(&a.weight))
+            .then(a_order.cmp(b_order))
+    });
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+pub fn write_book(path: &Path, entries: &[BookEntry]) -> anyhow::Result<()> {
+    let mut data = Vec::with_capacity(entries.len() * 16);
+    for entry in entries {
+        data.extend_from_slice(&entry.key.to_be_bytes());
+        data.extend_from_slice(&entry.mv.to_be_bytes());
+        data.extend_from_slice(&entry.weight.to_be_bytes());
+        data.extend_from_slice(&entry.learn.to_be_bytes());
+    }
+    fs::write(path, data)?;
+    Ok(())
+}
+
+pub fn read_book(path: &Path) -> anyhow::Result<Vec<BookEntry>> {
+    let data = fs::read(path)?;
+    if data.len() % 16 != 0 {
+        anyhow::bail!("{} isn't a polyglot book", path.display());
+    }
+    Ok(data
+        .chunks_exact(16)
+        .map(|x| BookEntry {
+            key: u64::from_be_bytes(x[0..8].try_into().unwrap()),
+            mv: u16::from_be_bytes(x[8..10].try_into().unwrap()),
+            weight: u16::from_be_bytes(x[10..12].try_into().unwrap()),
+            learn: u32::from_be_bytes(x[12..16].try_into().unwrap()),
+        })
+        .collect())
+}
+
+/// Turn a book into prep for `color`. The heaviest move in each position is the main line, and
+/// for our moves anything lighter is marked as an alternative. Positions are only followed the
+/// first time they're reached, so transposed lines stop where they join another.
+pub fn book_graph(entries: &[BookEntry], color: Color) -> OpeningGraph {
+    let mut by_key: BTreeMap<u64, Vec<&BookEntry>> = BTreeMap::new();
+    for entry in entries.iter().filter(|x| x.weight > 0) {
+        by_key.entry(entry.key).or_default().push(entry);
+    }
+    for moves in by_key.values_mut() {
+        // Stable so books already in weight order keep their order
+        moves.sort_by_key(|x| Reverse(x.weight));
+    }
+
+    let mut graph = OpeningGraph::default();
+    let mut seen = HashSet::new();
+    let mut stack: Vec<(Chess, Option<NodeIndex>, usize)> = vec![(Chess::default(), None, 0)];
+    while let Some((position, parent, plies)) = stack.pop() {
+        let key = hash(&position);
+        if plies >= BOOK_PLIES || !seen.insert(key) {
+            continue;
+        }
+        let Some(moves) = by_key.get(&key) else {
+            continue;
+        };
+        let best = moves[0].weight;
+        let mut children = vec![];
+        for entry in moves {
+            let Some(mv) = decode_move(&position, entry.mv) else {
+                continue;
+            };
+            let mut node = OpeningNode::from(SanPlus::from_move(position.clone(), &mv));
+            if position.turn() == color && entry.weight < best {
+                node.priority = Priority::Alternative;
+            }
+            let index = graph.add_move(parent, node);
+            let mut next = position.clone();
+            next.play_unchecked(&mv);
+            children.push((next, Some(index), plies + 1));
+        }
+        // Depth first so the indexes follow the main line like they do for PGNs
+        stack.extend(children.into_iter().rev());
+    }
+    graph
+}
+
+pub(crate) fn hash(position: &Chess) -> u64 {
+    position.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0
+}
+
+/// Polyglot packs moves as to file, to rank, from file, from rank and promotion with 3 bits each.
+/// Castling is written as the king taking its own rook.
+pub(crate) fn encode_move(mv: &Move) -> u16 {
+    let (from, to) = match mv {
+        Move::Castle { king, rook } => (*king, *rook),
+        _ => (mv.from().unwrap_or(mv.to()), mv.to()),
+    };
+    let promotion = match mv.promotion() {
+        Some(Role::Knight) => 1,
+        Some(Role::Bishop) => 2,
+        Some(Role::Rook) => 3,
+        Some(Role::Queen) => 4,
+        _ => 0,
+    };
+    let square = |sq: Square| ((sq.rank() as u16) << 3) | sq.file() as u16;
+    square(to) | (square(from) << 6) | (promotion << 12)
+}
+
+pub(crate) fn decode_move(position: &Chess, mv: u16) -> Option<Move> {
+    position
+        .legal_moves()
+        .into_iter()
+        .find(|x| encode_move(x) == mv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{line_key, lines, OpeningDatabase, Prep};
+    use std::path::Path;
+
+    #[test]
+    fn encoding() {
+        let position = Chess::default();
+        let e4 = SanPlus::from_ascii(b"e4")
+            .unwrap()
+            .san
+            .to_move(&position)
+            .unwrap();
+        // e2e4 from the polyglot spec
+        assert_eq!(encode_move(&e4), 0x031c);
+        assert_eq!(decode_move(&position, 0x031c), Some(e4));
+        assert_eq!(hash(&position), 0x463b96181691fc9c);
+    }
+
+    #[test]
+    fn round_trip() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let mut prep = db.prep(Color::White);
+        let line = "e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5 Qe2";
+        crate::edit::Edit::SetPriority {
+            line: line.to_string(),
+            priority: Priority::Alternative,
+        }
+        .apply(Color::White, &mut prep)
+        .unwrap();
+
+        let entries = export_book(&prep.graph, BookWeights::Priority);
+        let folder =
+            std::env::temp_dir().join(format!("chess-driller-book-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let file = folder.join("vienna.bin");
+        write_book(&file, &entries).unwrap();
+        let read = read_book(&file).unwrap();
+        assert_eq!(read, entries);
+        assert!(read.windows(2).all(|x| x[0].key <= x[1].key));
+
+        let graph = book_graph(&read, Color::White);
+        let keys = |graph: &OpeningGraph| {
+            lines(graph)
+                .iter()
+                .map(|x| line_key(graph, x))
+                .collect::<Vec<_>>()
+        };
+        // Making Qe2 an alternative puts d4 first in the book
+        let mut expected = keys(&prep.graph);
+        let mut imported = keys(&graph);
+        assert_ne!(imported[0], expected[0]);
+        expected.sort();
+        imported.sort();
+        assert_eq!(imported, expected);
+
+        let priorities = |graph: &OpeningGraph| {
+            graph
+                .node_indices()
+                .filter(|x| graph[*x].priority == Priority::Alternative)
+                .map(|x| graph[x].san.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(priorities(&graph), priorities(&prep.graph));
+
+        // Books in the repertoire folders are loaded as a chapter
+        let loaded = Prep::load(Color::White, std::slice::from_ref(&folder)).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(loaded.chapters[0].name, "vienna");
+        assert_eq!(loaded.graph.node_count(), graph.node_count());
+    }
+}
@@ -0,0 +1,314 @@
+//! Prepare for a particular opponent by comparing their games with our repertoire, so we know which
+//! of our lines they're likely to go into and where they'll take us out of them.
+use crate::db::{line_key, path_to, OpeningDatabase};
+use crate::eco::Classifier;
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use pgn_reader::SanPlus;
+use serde::Serialize;
+use shakmaty::{Chess, Color};
+use std::collections::HashMap;
+use std::fmt;
+
+/// How many lines and deviations are shown in the printed report
+const REPORT_ROWS: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LikelyLine {
+    pub line: String,
+    pub opening: Option<String>,
+    /// Chance of the opponent's moves taking us to the end of the line
+    pub likelihood: f32,
+    /// Their games that followed the whole line
+    pub games: u32,
+}
+
+/// A move the opponent plays in a position from our repertoire that we've got no prep for
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Deviation {
+    /// Moves before theirs
+    pub line: String,
+    pub played: String,
+    pub opening: Option<String>,
+    pub games: u32,
+    /// Fraction of their games from the position where they played it
+    pub share: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ScoutReport {
+    pub opponent: String,
+    /// The repertoire we compared their games to
+    #[serde(skip)]
+    pub color: Color,
+    /// How many games they played against the colour of the repertoire
+    pub games: u32,
+    /// Our lines they can reach, most likely first
+    pub lines: Vec<LikelyLine>,
+    /// Where they leave our prep, most common first
+    pub deviations: Vec<Deviation>,
+}
+
+impl fmt::Display for ScoutReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} has {} games against our {} repertoire",
+            self.opponent, self.games, self.color
+        )?;
+        writeln!(f, "Lines they're likely to enter:")?;
+        for line in self.lines.iter().take(REPORT_ROWS) {
+            write!(
+                f,
+                "  {:>5.1}% ({} games) {}",
+                line.likelihood * 100.0,
+                line.games,
+                line.line
+            )?;
+            match line.opening.as_ref() {
+                Some(opening) => writeln!(f, " ({})", opening)?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(f, "Where they leave the prep:")?;
+        for deviation in self.deviations.iter().take(REPORT_ROWS) {
+            let line = if deviation.line.is_empty() {
+                "<start>"
+            } else {
+                deviation.line.as_str()
+            };
+            write!(
+                f,
+                "  {} games ({:.0}%) after {}: {}",
+                deviation.games,
+                deviation.share * 100.0,
+                line,
+                deviation.played
+            )?;
+            match deviation.opening.as_ref() {
+                Some(opening) => writeln!(f, " ({})", opening)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Children of a node in move order, or the roots from the usual starting position
+fn children(openings: &OpeningGraph, node: Option<NodeIndex>) -> Vec<NodeIndex> {
+    let mut res = match node {
+        Some(node) => openings
+            .neighbors_directed(node, Direction::Outgoing)
+            .collect::<Vec<_>>(),
+        None => openings
+            .roots()
+            .iter()
+            .copied()
+            .filter(|x| openings[*x].start().is_none())
+            .collect(),
+    };
+    res.sort();
+    res
+}
+
+/// Compare the `games` of an opponent with our `color` repertoire, only their games with the
+/// other colour are used.
+pub fn scout(
+    opponent: &str,
+    color: Color,
+    repertoire: &OpeningGraph,
+    games: &OpeningDatabase,
+) -> ScoutReport {
+    let theirs = games.graph(!color);
+    let classifier = Classifier::bundled();
+    let key = |node: Option<NodeIndex>| {
+        node.map(|x| line_key(repertoire, &path_to(repertoire, x)))
+            .unwrap_or_default()
+    };
+    let their_roots = children(theirs, None);
+    let total_games = their_roots.iter().map(|x| theirs[*x].games).sum();
+
+    let mut lines = vec![];
+    let mut deviations = vec![];
+    // Our node, their moves from the same position, how likely we are to get here and whose move
+    let mut stack = vec![(None, their_roots, total_games, 1.0, Color::White)];
+    while let Some((node, their_moves, games, likelihood, turn)) = stack.pop() {
+        let ours = children(repertoire, node);
+        if ours.is_empty() {
+            if let Some(node) = node.filter(|_| likelihood > 0.0) {
+                lines.push(LikelyLine {
+                    line: key(Some(node)),
+                    opening: classifier
+                        .classify_node(repertoire, node)
+                        .map(|x| x.to_string()),
+                    likelihood,
+                    games,
+                });
+            }
+            continue;
+        }
+        let total = their_moves.iter().map(|x| theirs[*x].games).sum::<u32>();
+        let prepped = ours.len();
+        if turn != color {
+            for their_move in &their_moves {
+                let san = &theirs[*their_move].san;
+                if ours.iter().any(|x| repertoire[*x].san == *san) {
+                    continue;
+                }
+                let mut moves = node
+                    .map(|x| path_to(repertoire, x))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| repertoire[x].san.clone())
+                    .collect::<Vec<SanPlus>>();
+                moves.push(san.clone());
+                deviations.push(Deviation {
+                    line: key(node),
+                    played: san.to_string(),
+                    opening: classifier
+                        .classify(&Chess::default(), &moves)
+                        .map(|x| x.to_string()),
+                    games: theirs[*their_move].games,
+                    share: theirs[*their_move].games as f32 / total as f32,
+                });
+            }
+        }
+        for child in ours.into_iter().rev() {
+            let matched = their_moves
+                .iter()
+                .copied()
+                .find(|x| theirs[*x].san == repertoire[child].san);
+            let games = matched.map(|x| theirs[x].games).unwrap_or(0);
+            let likelihood = if turn == color {
+                likelihood
+            } else if total > 0 {
+                likelihood * games as f32 / total as f32
+            } else {
+                // Past the end of their games so each move we have prep for is as likely
+                likelihood / prepped as f32
+            };
+            let next = matched
+                .map(|x| children(theirs, Some(x)))
+                .unwrap_or_default();
+            stack.push((Some(child), next, games, likelihood, !turn));
+        }
+    }
+    lines.sort_by(|a, b| {
+        b.likelihood
+            .total_cmp(&a.likelihood)
+            .then(a.line.cmp(&b.line))
+    });
+    deviations.sort_by(|a, b| b.games.cmp(&a.games).then(a.line.cmp(&b.line)));
+    ScoutReport {
+        opponent: opponent.to_string(),
+        color,
+        games: total_games,
+        lines,
+        deviations,
+    }
+}
+
+/// How many times the opponent played each of their moves in our `color` repertoire, used to
+/// drill against the moves they actually play.
+pub fn move_weights(
+    color: Color,
+    repertoire: &OpeningGraph,
+    games: &OpeningDatabase,
+) -> HashMap<NodeIndex, u64> {
+    let theirs = games.graph(!color);
+    let mut weights = HashMap::new();
+    let mut stack = vec![(None, None, Color::White)];
+    while let Some((node, their_node, turn)) = stack.pop() {
+        let their_moves = children(theirs, their_node);
+        for child in children(repertoire, node) {
+            let matched = their_moves
+                .iter()
+                .copied()
+                .find(|x| theirs[*x].san == repertoire[child].san);
+            let Some(matched) = matched else {
+                continue;
+            };
+            if turn != color {
+                weights.insert(child, u64::from(theirs[matched].games));
+            }
+            stack.push((Some(child), Some(matched), !turn));
+        }
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MoveAssessment;
+    use std::path::Path;
+
+    fn games(moves: &[(&str, usize)]) -> String {
+        let mut res = String::new();
+        for (line, count) in moves {
+            for _ in 0..*count {
+                res.push_str(&format!(
+                    "[White \"Someone\"]\n[Black \"Rival\"]\n\n{} *\n\n",
+                    line
+                ));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn scout_opponent() {
+        let pgn = games(&[
+            ("1. e4 e5 2. Nc3 Nf6 3. f4 d5 4. fxe5 Nxe4 5. Nf3 Bc5", 3),
+            ("1. e4 e5 2. Nc3 Nf6 3. f4 exf4", 1),
+            ("1. e4 c6 2. d4 d5", 4),
+        ]);
+        // Games with the other colour are left out
+        let pgn = format!(
+            "[White \"Rival\"]\n[Black \"Someone\"]\n\n1. e4 c5 *\n\n{}",
+            pgn
+        );
+        let opponent =
+            OpeningDatabase::load_multigame_pgn(pgn.as_bytes(), "rival".to_string()).unwrap();
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::White);
+
+        let report = scout("rival", Color::White, graph, &opponent);
+        assert_eq!(report.games, 8);
+        let deviations = report
+            .deviations
+            .iter()
+            .map(|x| (x.line.as_str(), x.played.as_str(), x.games))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            deviations,
+            vec![("e4", "c6", 4), ("e4 e5 Nc3 Nf6 f4", "exf4", 1)]
+        );
+        assert_eq!(
+            report.deviations[0].opening.as_deref(),
+            Some("B10 Caro-Kann Defense")
+        );
+        assert_eq!(report.deviations[1].share, 0.25);
+        // Half their games go into the Vienna and three quarters of those play d5
+        let likely = &report.lines[0];
+        assert!(likely
+            .line
+            .starts_with("e4 e5 Nc3 Nf6 f4 d5 fxe5 Nxe4 Nf3 Bc5"));
+        assert_eq!(likely.likelihood, 0.375);
+        assert_eq!(likely.games, 0);
+
+        // Drilling against them only picks the moves they've played
+        let weights = move_weights(Color::White, graph, &opponent);
+        for _ in 0..10 {
+            let mut state = db.start_drill(Color::White, &[]).unwrap();
+            state.set_move_weights(weights.clone());
+            for mv in ["e4", "Nc3", "f4", "fxe5", "Nf3"] {
+                let mv = SanPlus::from_ascii(mv.as_bytes()).unwrap();
+                assert_eq!(state.apply_move(&mv, graph), MoveAssessment::InPrep);
+                let reply = state.make_move(graph).unwrap().to_string();
+                assert!(["e5", "Nf6", "d5", "Nxe4", "Bc5"].contains(&reply.as_str()));
+            }
+        }
+    }
+}
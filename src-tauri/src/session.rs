@@ -0,0 +1,209 @@
+//! Everything played on the board since it was last reset, so a session can be looked back over
+//! or saved as a PGN with comments showing where the prep ended.
+use crate::db::{ply_offset, setup_position, MoveAssessment};
+use chrono::{DateTime, Local};
+use pgn_reader::SanPlus;
+use shakmaty::fen::Fen;
+use shakmaty::{Chess, Color, Position};
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionMove {
+    pub san: SanPlus,
+    /// How the move compared to the prep, `None` if no drill was running
+    pub assessment: Option<MoveAssessment>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// The FEN the board was set up from, `None` for the usual starting position
+    pub start: Option<Fen>,
+    pub moves: Vec<SessionMove>,
+    pub started: DateTime<Local>,
+}
+
+/// Where a session came from, written to the headers of the exported PGN
+#[derive(Clone, Debug)]
+pub struct SessionSource<'a> {
+    /// The side the player moved for
+    pub side: Color,
+    pub repertoire: &'a [PathBuf],
+    pub engine: Option<&'a PathBuf>,
+}
+
+impl Session {
+    pub fn new(start: Option<Fen>) -> Self {
+        Self {
+            start,
+            moves: vec![],
+            started: Local::now(),
+        }
+    }
+
+    pub fn push(&mut self, san: SanPlus, assessment: Option<MoveAssessment>) {
+        self.moves.push(SessionMove { san, assessment });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    pub fn sans(&self) -> Vec<SanPlus> {
+        self.moves.iter().map(|x| x.san.clone()).collect()
+    }
+
+    pub fn start_position(&self) -> Chess {
+        self.start
+            .as_ref()
+            .and_then(|x| setup_position(x).ok())
+            .unwrap_or_default()
+    }
+
+    /// Write the session out as a PGN game. A comment follows the last move in prep, or the move
+    /// that left it.
+    pub fn to_pgn(&self, source: &SessionSource) -> String {
+        let mut position = self.start_position();
+        let first_ply = ply_offset(&position);
+        for mv in &self.moves {
+            if let Ok(m) = mv.san.san.to_move(&position) {
+                position.play_unchecked(&m);
+            }
+        }
+        let result = position
+            .outcome()
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "*".to_string());
+        let mut res = String::new();
+        let mut header = |name: &str, value: &str| {
+            res.push_str(&format!("[{} \"{}\"]\n", name, value.replace('"', "'")));
+        };
+        let (white, black) = match source.side {
+            Color::White => ("Player", "chess-driller"),
+            Color::Black => ("chess-driller", "Player"),
+        };
+        let repertoire = source
+            .repertoire
+            .iter()
+            .map(|x| x.display().to_string())
+            .collect::<Vec<_>>();
+        header("Event", "Drill session");
+        header("Site", "chess-driller");
+        header("Date", &self.started.format("%Y.%m.%d").to_string());
+        header("White", white);
+        header("Black", black);
+        header("Result", &result);
+        header("Repertoire", &repertoire.join(", "));
+        if let Some(engine) = source.engine {
+            header("Engine", &engine.display().to_string());
+        }
+        if let Some(start) = self.start.as_ref() {
+            header("SetUp", "1");
+            header("FEN", &start.to_string());
+        }
+
+        let mut movetext = vec![];
+        let mut number_next = true;
+        for (i, mv) in self.moves.iter().enumerate() {
+            let ply = first_ply + i;
+            if ply.is_multiple_of(2) {
+                movetext.push(format!("{}.", ply / 2 + 1));
+            } else if number_next {
+                movetext.push(format!("{}...", ply / 2 + 1));
+            }
+            movetext.push(mv.san.to_string());
+            number_next = false;
+            let next = self.moves.get(i + 1).and_then(|x| x.assessment);
+            let comment = match (mv.assessment, next) {
+                (Some(MoveAssessment::OutOfPrep), _) => Some("Out of prep"),
+                (Some(MoveAssessment::InPrep), Some(MoveAssessment::PrepEnded)) => {
+                    Some("End of prep")
+                }
+                _ => None,
+            };
+            if let Some(comment) = comment {
+                movetext.push(format!("{{ {} }}", comment));
+                number_next = true;
+            }
+        }
+        movetext.push(result);
+        res.push('\n');
+        res.push_str(&movetext.join(" "));
+        res.push('\n');
+        res
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OpeningDatabase;
+
+    fn session(start: Option<&str>, moves: &[(&str, Option<MoveAssessment>)]) -> Session {
+        let mut session = Session::new(start.map(|x| x.parse().unwrap()));
+        for (san, assessment) in moves {
+            session.push(SanPlus::from_ascii(san.as_bytes()).unwrap(), *assessment);
+        }
+        session
+    }
+
+    #[test]
+    fn export_sessions() {
+        let folders = [PathBuf::from("prep/white")];
+        let engine = PathBuf::from("/usr/bin/stockfish");
+        let source = SessionSource {
+            side: Color::White,
+            repertoire: &folders,
+            engine: Some(&engine),
+        };
+        let in_prep = Some(MoveAssessment::InPrep);
+        let drilled = session(
+            None,
+            &[
+                ("e4", in_prep),
+                ("e5", in_prep),
+                ("Nc3", Some(MoveAssessment::PrepEnded)),
+                ("Nf6", None),
+            ],
+        );
+        let pgn = drilled.to_pgn(&source);
+        assert!(pgn.contains("[White \"Player\"]\n[Black \"chess-driller\"]\n"));
+        assert!(pgn.contains("[Repertoire \"prep/white\"]\n[Engine \"/usr/bin/stockfish\"]\n"));
+        assert!(pgn.ends_with("\n1. e4 e5 { End of prep } 2. Nc3 Nf6 *\n"));
+        // It can be read back in
+        let db = OpeningDatabase::load_multigame_pgn(pgn.as_bytes(), "Player".to_string()).unwrap();
+        assert_eq!(db.graph(Color::White).node_count(), 4);
+
+        let source = SessionSource {
+            side: Color::Black,
+            repertoire: &[],
+            engine: None,
+        };
+        let mate = session(
+            None,
+            &[
+                ("f3", in_prep),
+                ("e5", Some(MoveAssessment::OutOfPrep)),
+                ("g4", None),
+                ("Qh4#", None),
+            ],
+        );
+        let pgn = mate.to_pgn(&source);
+        assert!(!pgn.contains("Engine"));
+        assert!(pgn.contains("[Result \"0-1\"]"));
+        assert!(pgn.ends_with("\n1. f3 e5 { Out of prep } 2. g4 Qh4# 0-1\n"));
+
+        let set_up = session(
+            Some("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"),
+            &[("Nf3", None), ("Nc6", None)],
+        );
+        let pgn = set_up.to_pgn(&source);
+        assert!(pgn.contains("[SetUp \"1\"]\n[FEN \"rnbqkbnr/"));
+        assert!(pgn.ends_with("\n2. Nf3 Nc6 *\n"));
+    }
+}
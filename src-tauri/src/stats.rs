@@ -0,0 +1,413 @@
+//! Keep track of how the player does in drills and work out how much of their repertoire they
+//! actually know.
+use crate::config::Config;
+use crate::db::{key_starts_with, line_key, lines, moves_key, path_to};
+use crate::eco::Classifier;
+use crate::store::{load_json, save_json};
+use crate::tree::OpeningGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use serde::{Deserialize, Serialize};
+use shakmaty::fen::Fen;
+use shakmaty::{Color, Position};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How many of the weakest positions to report
+const WEAKEST_POSITIONS: usize = 10;
+
+/// How the player has done in a position where they had to pick a move
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PositionRecord {
+    pub attempts: u32,
+    pub correct: u32,
+    /// Number of attempts where the player asked for a hint
+    pub hints: u32,
+}
+
+impl PositionRecord {
+    pub fn accuracy(&self) -> Option<f32> {
+        (self.attempts > 0).then(|| self.correct as f32 / self.attempts as f32)
+    }
+}
+
+/// Review scores of the drill rounds that ended in a position
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoundRecord {
+    pub rounds: u32,
+    /// The rounds' review scores added up, in percent
+    pub total_score: u32,
+}
+
+/// Every move the player has made in a drill, positions are keyed by the moves leading to them
+/// (see [`line_key`]) so the history survives changes to the prep.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrillHistory {
+    #[serde(default)]
+    white: BTreeMap<String, PositionRecord>,
+    #[serde(default)]
+    black: BTreeMap<String, PositionRecord>,
+    /// Rounds keyed by the last position in the prep they reached
+    #[serde(default)]
+    white_rounds: BTreeMap<String, RoundRecord>,
+    #[serde(default)]
+    black_rounds: BTreeMap<String, RoundRecord>,
+}
+
+impl DrillHistory {
+    pub fn load(config: &Config) -> Self {
+        load_json(&config.data_dir().join("history.json"))
+    }
+
+    pub fn save(&self, config: &Config) -> anyhow::Result<()> {
+        save_json(&config.data_dir().join("history.json"), self)
+    }
+
+    pub fn positions(&self, color: Color) -> &BTreeMap<String, PositionRecord> {
+        match color {
+            Color::White => &self.white,
+            Color::Black => &self.black,
+        }
+    }
+
+    /// Record the player's move in the position after `node`, or the drill's `start` if there's
+    /// no node.
+    pub fn record(
+        &mut self,
+        color: Color,
+        openings: &OpeningGraph,
+        start: Option<&Fen>,
+        node: Option<NodeIndex>,
+        correct: bool,
+        hinted: bool,
+    ) {
+        let key = position_key(openings, start, node);
+        let positions = match color {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        };
+        let record = positions.entry(key).or_default();
+        record.attempts += 1;
+        if correct {
+            record.correct += 1;
+        }
+        if hinted {
+            record.hints += 1;
+        }
+    }
+
+    pub fn rounds(&self, color: Color) -> &BTreeMap<String, RoundRecord> {
+        match color {
+            Color::White => &self.white_rounds,
+            Color::Black => &self.black_rounds,
+        }
+    }
+
+    /// Record the review score of a round that ended after `node`, or at the drill's `start`,
+    /// see [`GameState::review_score`](crate::db::GameState::review_score)
+    pub fn record_round(
+        &mut self,
+        color: Color,
+        openings: &OpeningGraph,
+        start: Option<&Fen>,
+        node: Option<NodeIndex>,
+        score: f32,
+    ) {
+        let key = position_key(openings, start, node);
+        let rounds = match color {
+            Color::White => &mut self.white_rounds,
+            Color::Black => &mut self.black_rounds,
+        };
+        let record = rounds.entry(key).or_default();
+        record.rounds += 1;
+        record.total_score += (score.clamp(0.0, 1.0) * 100.0).round() as u32;
+    }
+}
+
+fn position_key(openings: &OpeningGraph, start: Option<&Fen>, node: Option<NodeIndex>) -> String {
+    match node {
+        Some(node) => line_key(openings, &path_to(openings, node)),
+        None => moves_key(start, []),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LineAccuracy {
+    pub line: String,
+    /// ECO code and name of the opening the line ends in
+    pub opening: Option<String>,
+    /// `None` if none of the player's moves in the line have been drilled
+    pub accuracy: Option<f32>,
+    /// Average review score of the rounds that ended somewhere along the line, `None` if there
+    /// weren't any
+    pub review_score: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PositionAccuracy {
+    /// Moves leading to the position
+    pub position: String,
+    pub attempts: u32,
+    pub accuracy: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RepertoireStats {
+    pub lines: usize,
+    /// Number of lines ending after each number of plies
+    pub depths: BTreeMap<usize, usize>,
+    /// Positions where the player has to pick a move
+    pub decision_positions: usize,
+    pub drilled_positions: usize,
+    /// Percentage of decision positions drilled at least once
+    pub drilled_percentage: f32,
+    pub line_accuracy: Vec<LineAccuracy>,
+    /// Drilled positions the player does worst in, worst first
+    pub weakest: Vec<PositionAccuracy>,
+}
+
+impl RepertoireStats {
+    pub fn new(color: Color, openings: &OpeningGraph, history: &DrillHistory) -> Self {
+        let records = history.positions(color);
+        let rounds = history.rounds(color);
+        let all_lines = lines(openings);
+
+        let mut depths = BTreeMap::new();
+        for line in &all_lines {
+            *depths.entry(line.len()).or_insert(0) += 1;
+        }
+
+        // The player decides the move when it's their turn, counting from whoever moves first
+        // where the line starts, and only if the prep has a move for them to find
+        let is_decision = |first: Color, plies: usize, node: NodeIndex| {
+            let turn = if plies.is_multiple_of(2) {
+                first
+            } else {
+                !first
+            };
+            let has_moves = openings
+                .neighbors_directed(node, Direction::Outgoing)
+                .next()
+                .is_some();
+            turn == color && has_moves
+        };
+
+        let mut decisions = BTreeMap::new();
+        let mut line_accuracy = vec![];
+        for line in &all_lines {
+            let root = &openings[line[0]];
+            let first = root.start_position().turn();
+            let mut attempts = 0;
+            let mut correct = 0;
+            // Every line has a first move so there's always one to find from the start
+            let mut positions = vec![];
+            if first == color {
+                positions.push(moves_key(root.start(), []));
+            }
+            for (i, node) in line.iter().enumerate() {
+                if is_decision(first, i + 1, *node) {
+                    positions.push(line_key(openings, &line[..=i]));
+                }
+            }
+            for key in positions {
+                let record = records.get(&key).copied();
+                if let Some(record) = record {
+                    attempts += record.attempts;
+                    correct += record.correct;
+                }
+                decisions.insert(key, record);
+            }
+            let key = line_key(openings, line);
+            let (played, total_score) = rounds
+                .iter()
+                .filter(|(ended, _)| key_starts_with(&key, ended))
+                .fold((0, 0), |(played, total), (_, x)| {
+                    (played + x.rounds, total + x.total_score)
+                });
+            line_accuracy.push(LineAccuracy {
+                line: key,
+                opening: Classifier::bundled()
+                    .classify_node(openings, line[line.len() - 1])
+                    .map(|x| x.to_string()),
+                accuracy: (attempts > 0).then(|| correct as f32 / attempts as f32),
+                review_score: (played > 0).then(|| total_score as f32 / (100 * played) as f32),
+            });
+        }
+
+        let decision_positions = decisions.len();
+        let drilled_positions = decisions.values().filter(|x| x.is_some()).count();
+        let drilled_percentage = if decision_positions == 0 {
+            100.0
+        } else {
+            100.0 * drilled_positions as f32 / decision_positions as f32
+        };
+
+        let mut weakest = decisions
+            .into_iter()
+            .filter_map(|(position, record)| {
+                let record = record?;
+                Some(PositionAccuracy {
+                    position,
+                    attempts: record.attempts,
+                    accuracy: record.accuracy()?,
+                })
+            })
+            .filter(|x| x.accuracy < 1.0)
+            .collect::<Vec<_>>();
+        weakest.sort_by(|a, b| {
+            a.accuracy
+                .total_cmp(&b.accuracy)
+                .then(b.attempts.cmp(&a.attempts))
+        });
+        weakest.truncate(WEAKEST_POSITIONS);
+
+        Self {
+            lines: all_lines.len(),
+            depths,
+            decision_positions,
+            drilled_positions,
+            drilled_percentage,
+            line_accuracy,
+            weakest,
+        }
+    }
+}
+
+impl fmt::Display for RepertoireStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Lines: {}", self.lines)?;
+        writeln!(f, "Line lengths (plies):")?;
+        for (depth, count) in &self.depths {
+            writeln!(f, "  {:>3}: {}", depth, count)?;
+        }
+        writeln!(
+            f,
+            "Drilled {}/{} positions where you pick the move ({:.1}%)",
+            self.drilled_positions, self.decision_positions, self.drilled_percentage
+        )?;
+        writeln!(f, "Line accuracy and review score:")?;
+        for line in &self.line_accuracy {
+            for value in [line.accuracy, line.review_score] {
+                match value {
+                    Some(value) => write!(f, "  {:>5.1}%", value * 100.0)?,
+                    None => write!(f, "      - ")?,
+                }
+            }
+            write!(f, " {}", line.line)?;
+            match line.opening.as_ref() {
+                Some(opening) => writeln!(f, " ({})", opening)?,
+                None => writeln!(f)?,
+            }
+        }
+        if !self.weakest.is_empty() {
+            writeln!(f, "Weakest positions:")?;
+            for position in &self.weakest {
+                let moves = if position.position.is_empty() {
+                    "<start>"
+                } else {
+                    position.position.as_str()
+                };
+                writeln!(
+                    f,
+                    "  {:>5.1}% over {} attempts after {}",
+                    position.accuracy * 100.0,
+                    position.attempts,
+                    moves
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OpeningDatabase;
+    use std::path::Path;
+
+    #[test]
+    fn set_up_positions() {
+        // Black moves first from here, after 1. e4
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let folder =
+            std::env::temp_dir().join(format!("chess-driller-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let pgn = format!(
+            "[Event \"Sicilian\"]\n[SetUp \"1\"]\n[FEN \"{}\"]\n\n1... c5 2. Nf3 d6 *\n\n\
+             [Event \"Reti\"]\n\n1. Nf3 d5 2. c4 *\n",
+            fen
+        );
+        std::fs::write(folder.join("setup.pgn"), pgn).unwrap();
+        let prep = crate::db::Prep::load(Color::White, std::slice::from_ref(&folder)).unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+        let graph = &prep.graph;
+
+        let keys = lines(graph)
+            .iter()
+            .map(|x| line_key(graph, x))
+            .collect::<Vec<_>>();
+        assert_eq!(keys[0], format!("{} | c5 Nf3 d6", fen));
+        let (start, moves) = crate::db::parse_line_key(&keys[0]).unwrap();
+        let start = start.unwrap();
+        assert_eq!(start.to_string(), fen);
+        let end = crate::db::find_line(graph, Some(&start), &moves).unwrap();
+        assert_eq!(line_key(graph, &path_to(graph, end)), keys[0]);
+        assert!(crate::db::find_line(graph, None, &moves).is_none());
+
+        // White picks the move after 1... c5 and at the start of the Reti, not before 1... c5
+        let mut history = DrillHistory::default();
+        history.record(Color::White, graph, Some(&start), None, false, false);
+        let stats = RepertoireStats::new(Color::White, graph, &history);
+        assert_eq!(stats.decision_positions, 3);
+        assert_eq!(stats.drilled_positions, 0);
+        let c5 = path_to(graph, end)[0];
+        history.record(Color::White, graph, Some(&start), Some(c5), true, false);
+        history.record(Color::White, graph, None, None, false, false);
+        let stats = RepertoireStats::new(Color::White, graph, &history);
+        assert_eq!(stats.drilled_positions, 2);
+        assert_eq!(stats.line_accuracy[0].accuracy, Some(1.0));
+        assert_eq!(stats.line_accuracy[1].accuracy, Some(0.0));
+
+        // A round ending at the start of the Sicilian says nothing about the Reti
+        history.record_round(Color::White, graph, Some(&start), None, 0.0);
+        let stats = RepertoireStats::new(Color::White, graph, &history);
+        assert_eq!(stats.line_accuracy[0].review_score, Some(0.0));
+        assert_eq!(stats.line_accuracy[1].review_score, None);
+    }
+
+    #[test]
+    fn drilled_positions() {
+        let db = OpeningDatabase::load(Path::new("prep")).unwrap();
+        let graph = db.graph(Color::White);
+        let mut history = DrillHistory::default();
+
+        let stats = RepertoireStats::new(Color::White, graph, &history);
+        assert_eq!(stats.drilled_positions, 0);
+        assert!(stats.line_accuracy.iter().all(|x| x.accuracy.is_none()));
+
+        // Get the first move right and the second one wrong
+        let line = &lines(graph)[0];
+        history.record(Color::White, graph, None, None, true, false);
+        history.record(Color::White, graph, None, Some(line[1]), false, true);
+
+        let stats = RepertoireStats::new(Color::White, graph, &history);
+        assert_eq!(stats.drilled_positions, 2);
+        assert_eq!(stats.line_accuracy[0].accuracy, Some(0.5));
+        assert_eq!(stats.weakest.len(), 1);
+        assert_eq!(stats.weakest[0].position, "e4 e5");
+
+        // A round that ended early counts for every line it could have been
+        assert!(stats.line_accuracy.iter().all(|x| x.review_score.is_none()));
+        history.record_round(Color::White, graph, None, Some(line[1]), 0.5);
+        history.record_round(Color::White, graph, None, line.last().copied(), 1.0);
+        let stats = RepertoireStats::new(Color::White, graph, &history);
+        assert_eq!(stats.line_accuracy[0].review_score, Some(0.75));
+        let others = stats.line_accuracy[1..]
+            .iter()
+            .filter(|x| x.line.starts_with("e4 e5 "))
+            .collect::<Vec<_>>();
+        assert!(!others.is_empty());
+        assert!(others.iter().all(|x| x.review_score == Some(0.5)));
+    }
+}
@@ -0,0 +1,84 @@
+//! The JSON files kept in the data directory, like the drill history and learned lines. A file
+//! that can't be read is moved aside rather than overwritten by the next save, and saves go
+//! through a temporary file so they're never left half written.
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+/// Where a file that couldn't be read is moved to
+pub fn bad_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(".bad");
+    file.with_file_name(name)
+}
+
+/// Load a JSON file, if it's missing or broken this starts again from the default. A broken file
+/// is kept next to it with a `.bad` extension.
+pub fn load_json<T: DeserializeOwned + Default>(file: &Path) -> T {
+    let data = match fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return T::default(),
+        Err(e) => {
+            warn!("Couldn't read {}: {}", file.display(), e);
+            return T::default();
+        }
+    };
+    match serde_json::from_slice(&data) {
+        Ok(value) => value,
+        Err(e) => {
+            let bad = bad_path(file);
+            warn!(
+                "Couldn't read {}, moving it to {}: {}",
+                file.display(),
+                bad.display(),
+                e
+            );
+            if let Err(e) = fs::rename(file, &bad) {
+                error!("Couldn't move {}: {}", file.display(), e);
+            }
+            T::default()
+        }
+    }
+}
+
+/// Save a JSON file, it's written to a temporary file and moved into place
+pub fn save_json<T: Serialize + ?Sized>(file: &Path, value: &T) -> anyhow::Result<()> {
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = file.with_extension("json.tmp");
+    fs::write(&temp, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("writing {}", temp.display()))?;
+    fs::rename(&temp, file).with_context(|| format!("replacing {}", file.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn broken_files_are_kept() {
+        let dir = std::env::temp_dir().join(format!("chess-driller-store-{}", std::process::id()));
+        let file = dir.join("history.json");
+        let missing: BTreeMap<String, u32> = load_json(&file);
+        assert!(missing.is_empty());
+
+        let value = BTreeMap::from([("e4".to_string(), 3)]);
+        save_json(&file, &value).unwrap();
+        assert!(!file.with_extension("json.tmp").exists());
+        assert_eq!(load_json::<BTreeMap<String, u32>>(&file), value);
+
+        fs::write(&file, "{ not json").unwrap();
+        let broken: BTreeMap<String, u32> = load_json(&file);
+        assert!(broken.is_empty());
+        assert!(!file.exists());
+        assert_eq!(fs::read_to_string(bad_path(&file)).unwrap(), "{ not json");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! The graph of moves behind a repertoire or a set of games. Besides the petgraph graph it keeps
+//! the roots and an index of each node's children by a 16-bit key for the move, so adding a game
+//! doesn't mean scanning the whole tree for where it starts.
+use crate::db::OpeningNode;
+use petgraph::graph::{Graph, NodeIndex};
+use petgraph::Direction;
+use pgn_reader::SanPlus;
+use shakmaty::fen::Fen;
+use shakmaty::san::San;
+use shakmaty::{CastlingSide, Role, Square};
+use std::collections::HashMap;
+use std::ops::{Deref, Index, IndexMut};
+
+/// Move keys for each destination square, see `move_key`
+const KEYS_PER_SQUARE: u16 = 370;
+
+/// Stands in for the parent of root moves in the child index
+const ROOT: u32 = u32::MAX;
+
+/// Packs a SAN into 16 bits. Checks and captures are left out as they follow from the position,
+/// the rest fits as there's only so many ways to write a move to each square. Drops, null moves
+/// and kings or pawns written with a square they move from have no key.
+pub fn move_key(san: &San) -> Option<u16> {
+    let key = match *san {
+        San::Normal {
+            role,
+            file,
+            rank,
+            to,
+            promotion,
+            ..
+        } => {
+            let key = match (role, promotion, file, rank) {
+                (Role::King, None, None, None) => 0,
+                (Role::Pawn, promotion, file, None) => {
+                    let promotion = match promotion {
+                        None => 0,
+                        Some(Role::Knight) => 1,
+                        Some(Role::Bishop) => 2,
+                        Some(Role::Rook) => 3,
+                        Some(Role::Queen) => 4,
+                        Some(_) => return None,
+                    };
+                    325 + promotion * 9 + file.map_or(0, |x| u16::from(x) + 1)
+                }
+                (Role::Knight | Role::Bishop | Role::Rook | Role::Queen, None, file, rank) => {
+                    let from = match (file, rank) {
+                        (None, None) => 0,
+                        (Some(file), None) => 1 + u16::from(file),
+                        (None, Some(rank)) => 9 + u16::from(rank),
+                        (Some(file), Some(rank)) => 17 + u16::from(Square::from_coords(file, rank)),
+                    };
+                    1 + (role as u16 - Role::Knight as u16) * 81 + from
+                }
+                _ => return None,
+            };
+            u16::from(to) * KEYS_PER_SQUARE + key
+        }
+        San::Castle(CastlingSide::KingSide) => 64 * KEYS_PER_SQUARE,
+        San::Castle(CastlingSide::QueenSide) => 64 * KEYS_PER_SQUARE + 1,
+        San::Put { .. } | San::Null => return None,
+    };
+    Some(key)
+}
+
+fn parent_key(parent: Option<NodeIndex>) -> u32 {
+    parent.map_or(ROOT, |x| x.index() as u32)
+}
+
+/// Read it like a petgraph graph, but add moves with `add_move` so they're indexed
+#[derive(Clone, Debug, Default)]
+pub struct OpeningGraph {
+    graph: Graph<OpeningNode, ()>,
+    /// Nodes without a parent, in the order they were added
+    roots: Vec<NodeIndex>,
+    /// The first child added for each move key, only a hint as moves can be written more than one
+    /// way
+    children: HashMap<(u32, u16), NodeIndex>,
+}
+
+impl Deref for OpeningGraph {
+    type Target = Graph<OpeningNode, ()>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
+
+impl Index<NodeIndex> for OpeningGraph {
+    type Output = OpeningNode;
+
+    fn index(&self, index: NodeIndex) -> &OpeningNode {
+        &self.graph[index]
+    }
+}
+
+/// Changing a node's move would leave the index out of date, everything else is fine
+impl IndexMut<NodeIndex> for OpeningGraph {
+    fn index_mut(&mut self, index: NodeIndex) -> &mut OpeningNode {
+        &mut self.graph[index]
+    }
+}
+
+/// Graphs are the same if they have the same nodes and edges in the same order
+impl PartialEq for OpeningGraph {
+    fn eq(&self, other: &Self) -> bool {
+        let edges = |x: &Self| {
+            x.raw_edges()
+                .iter()
+                .map(|e| (e.source(), e.target()))
+                .collect::<Vec<_>>()
+        };
+        self.node_weights().eq(other.node_weights()) && edges(self) == edges(other)
+    }
+}
+
+impl Eq for OpeningGraph {}
+
+impl From<Graph<OpeningNode, ()>> for OpeningGraph {
+    /// Index a graph that was put together some other way
+    fn from(graph: Graph<OpeningNode, ()>) -> Self {
+        let mut res = Self {
+            graph,
+            ..Default::default()
+        };
+        for node in res.graph.node_indices() {
+            let parent = res
+                .graph
+                .neighbors_directed(node, Direction::Incoming)
+                .next();
+            if parent.is_none() {
+                res.roots.push(node);
+            }
+            res.index_move(parent, node);
+        }
+        res
+    }
+}
+
+impl OpeningGraph {
+    pub fn roots(&self) -> &[NodeIndex] {
+        &self.roots
+    }
+
+    /// Add a move after `parent`, or as a root
+    pub fn add_move(&mut self, parent: Option<NodeIndex>, node: OpeningNode) -> NodeIndex {
+        let index = self.graph.add_node(node);
+        match parent {
+            Some(parent) => {
+                self.graph.add_edge(parent, index, ());
+            }
+            None => self.roots.push(index),
+        }
+        self.index_move(parent, index);
+        index
+    }
+
+    fn index_move(&mut self, parent: Option<NodeIndex>, node: NodeIndex) {
+        if let Some(key) = move_key(&self.graph[node].san.san) {
+            self.children
+                .entry((parent_key(parent), key))
+                .or_insert(node);
+        }
+    }
+
+    /// The child of `parent` for a move, roots also need to start from the same position
+    pub fn find_move(
+        &self,
+        parent: Option<NodeIndex>,
+        san: &SanPlus,
+        start: Option<&Fen>,
+    ) -> Option<NodeIndex> {
+        let matches = |node: NodeIndex| {
+            self.graph[node].san == *san && (parent.is_some() || self.graph[node].start() == start)
+        };
+        if let Some(key) = move_key(&san.san) {
+            let hint = self.children.get(&(parent_key(parent), key));
+            if let Some(&node) = hint.filter(|x| matches(**x)) {
+                return Some(node);
+            }
+        }
+        match parent {
+            Some(parent) => self
+                .graph
+                .neighbors_directed(parent, Direction::Outgoing)
+                .find(|x| matches(*x)),
+            None => self.roots.iter().copied().find(|x| matches(*x)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{Chess, Position};
+    use std::collections::HashSet;
+
+    fn keys(position: &Chess) -> Vec<u16> {
+        position
+            .legal_moves()
+            .iter()
+            .map(|mv| move_key(&San::from_move(position, mv)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn move_keys() {
+        // Every way of writing a move to a square gets its own key
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        for to in Square::ALL {
+            for role in [Role::Knight, Role::Bishop, Role::Rook, Role::Queen] {
+                for file in [None].into_iter().chain(shakmaty::File::ALL.map(Some)) {
+                    for rank in [None].into_iter().chain(shakmaty::Rank::ALL.map(Some)) {
+                        let san = San::Normal {
+                            role,
+                            file,
+                            rank,
+                            capture: false,
+                            to,
+                            promotion: None,
+                        };
+                        seen.insert(move_key(&san).unwrap());
+                        count += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(seen.len(), count);
+        let castle = move_key(&San::Castle(CastlingSide::KingSide)).unwrap();
+        assert!(!seen.contains(&castle));
+
+        let position = Chess::default();
+        let keys = keys(&position);
+        assert_eq!(keys.len(), keys.iter().collect::<HashSet<_>>().len());
+
+        // Lookups still go by the SAN, a check written on a move that isn't check is kept apart
+        let mut graph = OpeningGraph::default();
+        let e4 = SanPlus::from_ascii(b"e4").unwrap();
+        let root = graph.add_move(None, OpeningNode::from(e4.clone()));
+        let e5 = SanPlus::from_ascii(b"e5").unwrap();
+        let e5_check = SanPlus::from_ascii(b"e5+").unwrap();
+        let child = graph.add_move(Some(root), OpeningNode::from(e5.clone()));
+        assert_eq!(graph.find_move(None, &e4, None), Some(root));
+        assert_eq!(graph.find_move(Some(root), &e5, None), Some(child));
+        assert_eq!(graph.find_move(Some(root), &e5_check, None), None);
+        let check = graph.add_move(Some(root), OpeningNode::from(e5_check.clone()));
+        assert_eq!(graph.find_move(Some(root), &e5_check, None), Some(check));
+        assert_eq!(graph.find_move(Some(child), &e5, None), None);
+
+        let rebuilt = OpeningGraph::from((*graph).clone());
+        assert_eq!(rebuilt.roots(), graph.roots());
+        assert_eq!(rebuilt.children, graph.children);
+    }
+}
@@ -0,0 +1,122 @@
+//! Reload the repertoire when the PGN files in it change so the app doesn't need restarting.
+//! Any change reloads every folder for that colour, edits are then replayed on top.
+use crate::config::RepertoireFolders;
+use crate::db::Prep;
+use crate::edit::apply_edits;
+use crate::ChessState;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use shakmaty::Color;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use tracing::{error, info, warn};
+
+/// Editors tend to write files in a few steps so wait for things to settle before reloading
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Event sent to the UI with the colour of the repertoire that's been reloaded
+pub const REPERTOIRE_CHANGED: &str = "repertoire-changed";
+
+pub struct RepertoireWatcher {
+    watcher: RecommendedWatcher,
+    watched: Vec<PathBuf>,
+}
+
+impl RepertoireWatcher {
+    pub fn new(app: AppHandle) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            // If this fails the app is closing
+            let _ = tx.send(event);
+        })?;
+        thread::spawn(move || reload_loop(app, rx));
+        Ok(Self {
+            watcher,
+            watched: vec![],
+        })
+    }
+
+    /// Start watching the folders in the repertoire and stop watching any that were removed
+    pub fn watch(&mut self, folders: &RepertoireFolders) {
+        for folder in self.watched.drain(..) {
+            if let Err(e) = self.watcher.unwatch(&folder) {
+                warn!("Couldn't stop watching {}: {}", folder.display(), e);
+            }
+        }
+        for folder in folders.white.iter().chain(folders.black.iter()) {
+            let folder = canonical(folder);
+            match self.watcher.watch(&folder, RecursiveMode::Recursive) {
+                Ok(()) => self.watched.push(folder),
+                Err(e) => error!("Couldn't watch {}: {}", folder.display(), e),
+            }
+        }
+    }
+}
+
+/// Events come with absolute paths, so folders given relative to where the app started need to
+/// be made absolute to match them
+fn canonical(folder: &Path) -> PathBuf {
+    folder
+        .canonicalize()
+        .unwrap_or_else(|_| folder.to_path_buf())
+}
+
+fn reload_loop(app: AppHandle, rx: Receiver<notify::Result<Event>>) {
+    while let Ok(event) = rx.recv() {
+        let mut paths = vec![];
+        let mut next = Some(event);
+        while let Some(event) = next.take() {
+            match event {
+                Ok(event) if !event.kind.is_access() => paths.extend(event.paths),
+                Ok(_) => {}
+                Err(e) => error!("Watching repertoire failed: {}", e),
+            }
+            next = match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+        }
+        let state = app.state::<ChessState>();
+        let (folders, edits) = {
+            let app = state.0.lock().unwrap();
+            // Lines added from the app are already in the prep
+            paths.retain(|path| !app.wrote(path));
+            (app.config.active().repertoire.clone(), app.edits.clone())
+        };
+        if paths.is_empty() {
+            continue;
+        }
+        for color in Color::ALL {
+            let folders = folders.folders(color);
+            let changed = paths.iter().find(|path| {
+                folders
+                    .iter()
+                    .any(|folder| path.starts_with(canonical(folder)))
+            });
+            let Some(changed) = changed else {
+                continue;
+            };
+            info!(
+                "Reloading the whole {} repertoire as {} changed",
+                color,
+                changed.display()
+            );
+            // Do the loading without holding the lock so drills carry on while we parse
+            let mut prep = match Prep::load(color, folders) {
+                Ok(prep) => prep,
+                Err(e) => {
+                    error!("Couldn't reload {} repertoire: {}", color, e);
+                    continue;
+                }
+            };
+            apply_edits(&mut prep, color, edits.edits(color));
+            state.0.lock().unwrap().update_prep(color, prep);
+            if let Err(e) = app.emit_all(REPERTOIRE_CHANGED, color.to_string()) {
+                error!("Couldn't tell the UI about the reload: {}", e);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 import { useState, useEffect } from 'react'
 import { invoke } from '@tauri-apps/api'
+import { listen } from '@tauri-apps/api/event'
 
 import { Chessboard } from "react-chessboard";
 
@@ -7,14 +8,45 @@ function App() {
   const [game, setGame] = useState("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR")
   let [orientation, setOrientation] = useState("white")
   let [promotion] = useState("Q")
+  let [hint, setHint] = useState({})
+  let [learn, setLearn] = useState(null)
+  let [opening, setOpening] = useState(null)
+  // Loaded from the profile, the shortcuts don't change it until it's there
+  let [drillConfig, setDrillConfig] = useState(null)
+
+  let [message, setMessage] = useState(null)
 
   useEffect(function(){
     document.onkeypress = handleKeyUp
+    invoke("get_config", {  })
+      .then((config) => setDrillConfig(config.profiles[config.profile].drill))
+      .catch((error) => setMessage(error))
+    const unlisten = listen("repertoire-changed", (event) => {
+        setMessage(`Reloaded ${event.payload} repertoire`);
+        invoke("learn_status", {  }).then((response) => setLearn(response));
+    })
+    return () => { unlisten.then((f) => f()) }
   },[])
 
   function onPieceDrop(sourceSquare, targetSquare, piece){
     invoke('move_piece', { 'from': sourceSquare, 'to': targetSquare, "promotion": piece ?? "Q" })
       .then((response) => setGame(response))
+      .then(() => invoke("opening", {  }))
+      .then((response) => setOpening(response))
+      .then(() => invoke("learn_status", {  }))
+      .then((response) => setLearn(response))
+    setHint({})
+  }
+
+  function hintStyles() {
+    let styles = {}
+    if (hint.from) {
+      styles[hint.from] = { background: "rgba(255, 255, 0, 0.4)" }
+    }
+    if (hint.to) {
+      styles[hint.to] = { background: "rgba(0, 255, 0, 0.4)" }
+    }
+    return styles
   }
 
   function handleKeyUp(event) {
@@ -28,16 +60,105 @@ function App() {
           }
       } else if (event.key == "s") {
           invoke("start", {  })
-            .then((response) => setGame(response))
+            .then((response) => {
+                setGame(response.board);
+                setOrientation(response.color);
+                setLearn(null);
+            })
+            .then(() => invoke("opening", {  }))
+            .then((response) => setOpening(response))
+      } else if (event.key == "m") {
+          setDrillConfig((config) => {
+              if (!config) {
+                  return config;
+              }
+              const modes = ["fixed", "random", "alternating"];
+              const mode = modes[(modes.indexOf(config.color_mode) + 1) % modes.length];
+              const newConfig = { ...config, "color_mode": mode };
+              invoke("set_drill_config", { "config": newConfig });
+              return newConfig;
+          })
+      } else if (event.key == "o") {
+          setDrillConfig((config) => {
+              if (!config) {
+                  return config;
+              }
+              const newConfig = { ...config, "opponent_view": !config.opponent_view };
+              invoke("set_drill_config", { "config": newConfig });
+              return newConfig;
+          })
+      } else if (event.key == "g") {
+          setDrillConfig((config) => {
+              if (!config) {
+                  return config;
+              }
+              const newConfig = { ...config, "masters": !config.masters };
+              invoke("set_drill_config", { "config": newConfig });
+              return newConfig;
+          })
+      } else if (event.key == "l") {
+          invoke("learn_start", {  })
+            .then((response) => {
+                setLearn(response);
+                setGame("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR");
+            })
+      } else if (event.key == "n") {
+          invoke("learn_show", {  })
+            .then((response) => {
+                setGame(response.board);
+                setHint({ "san": response.san, "comments": response.comments });
+            })
+      } else if (event.key == "q") {
+          setHint({});
+          invoke("learn_quiz", {  })
+            .then((response) => {
+                setGame(response.board);
+                setOrientation(response.color);
+            })
+      } else if (event.key == "h") {
+          invoke("hint", {  })
+            .then((response) => setHint(response))
+      } else if (event.key == "a") {
+          invoke("add_to_repertoire", { "file": null })
+            .then((file) => setMessage(`Added line to ${file}`))
+            .catch((error) => setMessage(error))
+      } else if (event.key == "e") {
+          invoke("export_session", { "file": null })
+            .then((file) => setMessage(`Saved session to ${file}`))
+            .catch((error) => setMessage(error))
+      } else if (event.key == "c") {
+          invoke("get_config", {  })
+            .then((config) => {
+                const profile = config.profiles[config.profile];
+                const users = window.prompt("chess.com usernames", profile["chess.com"].join(", "));
+                if (users === null) {
+                    return;
+                }
+                profile["chess.com"] = users.split(",").map((x) => x.trim()).filter((x) => x);
+                return invoke("update_config", { "config": config })
+                  .then((config) => {
+                      setDrillConfig(config.profiles[config.profile].drill);
+                      setMessage("Saved settings");
+                  });
+            })
+            .catch((error) => setMessage(error))
       } else if (event.key == "r") {
           setGame("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR");
+          setHint({});
+          setOpening(null);
           invoke("reset", {"color": orientation }) 
       }
   }
 
   return (
     <div className="w-[100vmin] h-[100vmin]">
-      <Chessboard id="BasicBoard" position={game} onPieceDrop={onPieceDrop} boardOrientation={orientation} animationDuration="0"/>
+      <Chessboard id="BasicBoard" position={game} onPieceDrop={onPieceDrop} boardOrientation={orientation} animationDuration="0" customSquareStyles={hintStyles()}/>
+      {message && <p>{message}</p>}
+      {drillConfig && <p>Colour: {drillConfig.color_mode}{drillConfig.opponent_view && ", opponent's view"}{drillConfig.masters && ", masters' moves"}</p>}
+      {opening && <p>{opening.eco} {opening.name}</p>}
+      {learn && <p>Learned {learn.completed}/{learn.lines} lines ({learn.coverage.toFixed(0)}%){learn.opening && `: ${learn.opening}`}</p>}
+      {hint.san && <p>{hint.san}</p>}
+      {(hint.comments ?? []).map((comment, i) => <p key={i}>{comment}</p>)}
     </div>
   )
 }